@@ -1,9 +1,13 @@
 fn main() {
     println!("cargo:rerun-if-env-changed=TARGET");
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=src/transformation/opencv_resize.cpp");
-    println!("cargo:rerun-if-changed=src/transformation/mod.rs");
+    println!("cargo:rerun-if-changed=src/image/transformation/opencv_resize.cpp");
+    println!("cargo:rerun-if-changed=src/image/transformation/opencv.rs");
 
+    // Native library is needed only for OpenCV transformation backend
+    if std::env::var("CARGO_FEATURE_OPENCV").is_err() {
+        return;
+    }
 
     // cc:Build does not allow to create .so library.
     // Creating static library with static binding to OpenCV seems stupid.
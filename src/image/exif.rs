@@ -0,0 +1,552 @@
+//! Reading and removal of EXIF metadata, embedded in images as TIFF structure,
+//! and removal of XMP packets
+use crate::image::header::{u16_at, u32_at};
+use crate::image::{ImageFormat, Orientation};
+
+use std::convert::TryInto;
+use std::ops::Range;
+use std::str::FromStr;
+
+const ORIENTATION: u16 = 0x0112;
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const DATE_TIME: u16 = 0x0132;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+const INTEROPERABILITY_IFD: u16 = 0xA005;
+const THUMBNAIL_OFFSET: u16 = 0x0201;
+const THUMBNAIL_LENGTH: u16 = 0x0202;
+const XML_PACKET: u16 = 0x02BC;
+
+const APP1: u8 = 0xE1;
+/// Namespaces starting APP1 segments of JPEG with XMP packet and its extension
+const JPEG_XMP: &[&[u8]] = &[b"http://ns.adobe.com/xap/1.0/\0", b"http://ns.adobe.com/xmp/extension/\0"];
+/// Keyword starting iTXt chunk of PNG with XMP packet
+const PNG_XMP: &[u8] = b"XML:com.adobe.xmp\0";
+
+/// Tags of TIFF image's directory describing camera, author and place.
+/// Other tags describe the image itself, so they are kept
+const PRIVATE_TAGS: &[u16] = &[
+    0x010E, // ImageDescription
+    MAKE,
+    MODEL,
+    0x0131, // Software
+    DATE_TIME,
+    0x013B, // Artist
+    0x013C, // HostComputer
+    0x8298, // Copyright
+    XML_PACKET,
+    EXIF_IFD,
+    GPS_IFD,
+];
+
+/// Nested directories followed, deeper ones are considered malformed
+const MAX_DEPTH: usize = 4;
+
+/// Selected fields of EXIF metadata
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Exif {
+    pub orientation: Option<Orientation>,
+    /// Make and model
+    pub camera: Option<String>,
+    /// Original date and time, like "2020-05-01T12:00:00"
+    pub captured: Option<String>,
+}
+
+/// EXIF metadata removed from uploaded images
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strip {
+    Nothing,
+    /// Location only
+    Gps,
+    /// Everything except orientation, including XMP packets. Tags of TIFF images
+    /// describing the image itself are kept, only camera, author and place are removed
+    All,
+}
+
+impl FromStr for Strip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(Strip::Nothing),
+            "gps" => Ok(Strip::Gps),
+            "all" => Ok(Strip::All),
+            other => Err(format!("{} is not one of none, gps, all", other)),
+        }
+    }
+}
+
+/// Entry of image file directory
+struct Entry {
+    offset: usize,
+    tag: u16,
+    kind: u16,
+    count: u32,
+}
+
+/// TIFF structure: header followed by image file directories
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        match data.get(..4)? {
+            b"II*\0" => Some(Tiff { data, big_endian: false }),
+            b"MM\0*" => Some(Tiff { data, big_endian: true }),
+            _ => None,
+        }
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        u16_at(self.data, offset, self.big_endian)
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        u32_at(self.data, offset, self.big_endian)
+    }
+
+    fn first_directory(&self) -> Option<usize> {
+        Some(self.u32(4)? as usize)
+    }
+
+    fn entries(&self, directory: usize) -> Option<Vec<Entry>> {
+        let count = self.u16(directory)? as usize;
+        // Offset of the next directory must be in data too
+        self.data.get(directory..directory + 2 + count * 12 + 4)?;
+        (0..count)
+            .map(|index| {
+                let offset = directory + 2 + index * 12;
+                Some(Entry {
+                    offset,
+                    tag: self.u16(offset)?,
+                    kind: self.u16(offset + 2)?,
+                    count: self.u32(offset + 4)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Range of entry's values. Values of up to 4 bytes are stored in the entry itself
+    fn values(&self, entry: &Entry) -> Option<Range<usize>> {
+        let size = match entry.kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        };
+        let size = (entry.count as usize).checked_mul(size)?;
+        let start = if size <= 4 {
+            entry.offset + 8
+        } else {
+            self.u32(entry.offset + 8)? as usize
+        };
+        let range = start..start.checked_add(size)?;
+        self.data.get(range.clone())?;
+        Some(range)
+    }
+
+    /// The first value of SHORT or LONG entry
+    fn number(&self, entry: &Entry) -> Option<u32> {
+        let values = self.values(entry)?;
+        match entry.kind {
+            3 => Some(self.u16(values.start)? as u32),
+            4 | 13 => self.u32(values.start),
+            _ => None,
+        }
+    }
+
+    fn text(&self, entry: &Entry) -> Option<String> {
+        if entry.kind != 2 {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&self.data[self.values(entry)?]);
+        let text = text.trim_end_matches('\0').trim();
+        Some(text.to_string()).filter(|text| !text.is_empty())
+    }
+
+    /// Collects ranges of values of `entry` and of directories it points to
+    fn collect_entry(&self, entry: &Entry, depth: usize, ranges: &mut Vec<Range<usize>>) -> Option<()> {
+        if [EXIF_IFD, GPS_IFD, INTEROPERABILITY_IFD].contains(&entry.tag) {
+            self.collect_directory(self.number(entry)? as usize, depth + 1, ranges)?;
+        }
+        let values = self.values(entry)?;
+        // Values stored in entry are removed together with it
+        if values.len() > 4 {
+            ranges.push(values);
+        }
+        Some(())
+    }
+
+    /// Collects ranges of `directory`, its values and embedded thumbnail
+    fn collect_directory(&self, directory: usize, depth: usize, ranges: &mut Vec<Range<usize>>) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let entries = self.entries(directory)?;
+        let (mut thumbnail, mut thumbnail_length) = (None, None);
+        for entry in &entries {
+            self.collect_entry(entry, depth, ranges)?;
+            match entry.tag {
+                THUMBNAIL_OFFSET => thumbnail = self.number(entry),
+                THUMBNAIL_LENGTH => thumbnail_length = self.number(entry),
+                _ => {}
+            }
+        }
+        if let (Some(start), Some(length)) = (thumbnail, thumbnail_length) {
+            let range = start as usize..start as usize + length as usize;
+            self.data.get(range.clone())?;
+            ranges.push(range);
+        }
+        ranges.push(directory..directory + 2 + entries.len() * 12 + 4);
+        Some(())
+    }
+}
+
+/// Range of TIFF structure with EXIF metadata in image
+fn locate(format: ImageFormat, data: &[u8]) -> Option<Range<usize>> {
+    match format {
+        ImageFormat::Jpeg => jpeg_exif(data),
+        ImageFormat::Png => png_exif(data),
+        ImageFormat::Webp => webp_exif(data),
+        ImageFormat::Tiff => Some(0..data.len()),
+        ImageFormat::Gif | ImageFormat::Bmp => None,
+    }
+}
+
+/// Markers and payloads of JPEG segments, which precede frame's data
+fn jpeg_segments(data: &[u8]) -> Vec<(u8, Range<usize>)> {
+    let mut segments = vec![];
+    let mut offset = 2;
+    while data.get(offset) == Some(&0xFF) {
+        while data.get(offset + 1) == Some(&0xFF) {
+            offset += 1;
+        }
+        match data.get(offset + 1).copied() {
+            Some(0x01) | Some(0xD0..=0xD7) => offset += 2,
+            Some(0xD9) | Some(0xDA) | None => break,
+            Some(marker) => {
+                let payload = match u16_at(data, offset + 2, true) {
+                    Some(length) if length >= 2 => offset + 4..offset + 2 + length as usize,
+                    _ => break,
+                };
+                if data.get(payload.clone()).is_none() {
+                    break;
+                }
+                offset = payload.end;
+                segments.push((marker, payload));
+            }
+        }
+    }
+    segments
+}
+
+/// Four bytes of chunk type at `offset`
+fn chunk_type(data: &[u8], offset: usize) -> Option<[u8; 4]> {
+    data.get(offset..offset + 4)?.try_into().ok()
+}
+
+/// Types and data of PNG chunks before "IEND". Checksum follows data of every chunk
+fn png_chunks(data: &[u8]) -> Vec<([u8; 4], Range<usize>)> {
+    let mut chunks = vec![];
+    let mut offset = 8;
+    while let (Some(length), Some(kind)) = (u32_at(data, offset, true), chunk_type(data, offset + 4)) {
+        let chunk = offset + 8..offset + 8 + length as usize;
+        if &kind == b"IEND" || data.get(chunk.end..chunk.end + 4).is_none() {
+            break;
+        }
+        offset = chunk.end + 4;
+        chunks.push((kind, chunk));
+    }
+    chunks
+}
+
+/// Types and data of WebP chunks
+fn webp_chunks(data: &[u8]) -> Vec<([u8; 4], Range<usize>)> {
+    let mut chunks = vec![];
+    let mut offset = 12;
+    while let (Some(kind), Some(size)) = (chunk_type(data, offset), u32_at(data, offset + 4, false)) {
+        let chunk = offset + 8..offset + 8 + size as usize;
+        if data.get(chunk.clone()).is_none() {
+            break;
+        }
+        // Chunks are padded to even size
+        offset = chunk.end + (size as usize & 1);
+        chunks.push((kind, chunk));
+    }
+    chunks
+}
+
+/// Finds APP1 segment starting with "Exif\0\0"
+fn jpeg_exif(data: &[u8]) -> Option<Range<usize>> {
+    jpeg_segments(data).into_iter()
+        .find(|(marker, payload)| *marker == APP1 && data[payload.clone()].starts_with(b"Exif\0\0"))
+        .map(|(_, payload)| payload.start + 6..payload.end)
+}
+
+/// Finds "eXIf" chunk
+fn png_exif(data: &[u8]) -> Option<Range<usize>> {
+    png_chunks(data).into_iter()
+        .find(|(kind, _)| kind == b"eXIf")
+        .map(|(_, chunk)| chunk)
+}
+
+/// Finds "EXIF" chunk of extended format. Some encoders prefix it with "Exif\0\0"
+fn webp_exif(data: &[u8]) -> Option<Range<usize>> {
+    let (_, chunk) = webp_chunks(data).into_iter().find(|(kind, _)| kind == b"EXIF")?;
+    match data[chunk.clone()].starts_with(b"Exif\0\0") {
+        true => Some(chunk.start + 6..chunk.end),
+        false => Some(chunk),
+    }
+}
+
+/// Converts "2020:05:01 12:00:00" to "2020-05-01T12:00:00".
+/// Unknown time, written as zeros, and malformed time with non-ASCII characters are omitted
+fn date_time(value: String) -> Option<String> {
+    if !value.is_ascii() || value.bytes().all(|byte| byte == b'0' || byte == b':' || byte == b' ') {
+        return None;
+    }
+    let bytes = value.as_bytes();
+    if bytes.len() < 19 || bytes[4] != b':' || bytes[7] != b':' || bytes[10] != b' ' {
+        return Some(value);
+    }
+    Some(format!("{}-{}-{}T{}", &value[..4], &value[5..7], &value[8..10], &value[11..19]))
+}
+
+/// Reads selected EXIF fields of image in `format`.
+/// Missing or malformed metadata gives empty fields
+pub fn read(format: ImageFormat, data: &[u8]) -> Exif {
+    locate(format, data)
+        .and_then(|range| read_tiff(&data[range]))
+        .unwrap_or_default()
+}
+
+fn read_tiff(data: &[u8]) -> Option<Exif> {
+    let tiff = Tiff::new(data)?;
+    let mut exif = Exif::default();
+    let (mut make, mut model, mut modified) = (None, None, None);
+    for entry in tiff.entries(tiff.first_directory()?)? {
+        match entry.tag {
+            ORIENTATION => exif.orientation = tiff.number(&entry)
+                .filter(|orientation| (1..=8).contains(orientation))
+                .map(|orientation| Orientation(orientation as u8)),
+            MAKE => make = tiff.text(&entry),
+            MODEL => model = tiff.text(&entry),
+            DATE_TIME => modified = tiff.text(&entry),
+            EXIF_IFD => exif.captured = tiff.number(&entry)
+                .and_then(|directory| tiff.entries(directory as usize))
+                .and_then(|entries| entries.into_iter().find(|entry| entry.tag == DATE_TIME_ORIGINAL))
+                .and_then(|entry| tiff.text(&entry)),
+            _ => {}
+        }
+    }
+    exif.captured = exif.captured.or(modified).and_then(date_time);
+    exif.camera = match (make, model) {
+        // Model often repeats make, like "Canon" and "Canon EOS 5D"
+        (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => make.or(model),
+    };
+    Some(exif)
+}
+
+/// Removes entries of the first directory selected by `remove` with their values
+/// and nested directories. With `drop_next`, following directories are removed too.
+/// Removed bytes are zeroed, so offsets of the rest of data are kept
+///
+/// Returns None without changes if structure is malformed
+fn strip_tiff(data: &mut [u8], remove: impl Fn(u16) -> bool, drop_next: bool) -> Option<()> {
+    let (ranges, directory, rebuilt) = {
+        let tiff = Tiff::new(data)?;
+        let directory = tiff.first_directory()?;
+        let entries = tiff.entries(directory)?;
+        let mut ranges = vec![];
+        let mut kept = vec![];
+        for entry in &entries {
+            if remove(entry.tag) {
+                tiff.collect_entry(entry, 0, &mut ranges)?;
+            } else {
+                kept.push(entry.offset);
+            }
+        }
+        let mut next = tiff.u32(directory + 2 + entries.len() * 12)?;
+        if drop_next && next != 0 {
+            tiff.collect_directory(next as usize, 0, &mut ranges)?;
+            next = 0;
+        }
+        let mut rebuilt = if tiff.big_endian {
+            (kept.len() as u16).to_be_bytes().to_vec()
+        } else {
+            (kept.len() as u16).to_le_bytes().to_vec()
+        };
+        for offset in kept {
+            rebuilt.extend_from_slice(&data[offset..offset + 12]);
+        }
+        rebuilt.extend_from_slice(&if tiff.big_endian { next.to_be_bytes() } else { next.to_le_bytes() });
+        ranges.push(directory..directory + 2 + entries.len() * 12 + 4);
+        (ranges, directory, rebuilt)
+    };
+    for range in ranges {
+        data[range].iter_mut().for_each(|byte| *byte = 0);
+    }
+    data[directory..directory + rebuilt.len()].copy_from_slice(&rebuilt);
+    Some(())
+}
+
+/// Checksum of PNG chunks
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Updates checksum, which follows PNG `chunk`. It covers type and data of chunk
+fn update_crc(data: &mut [u8], chunk: Range<usize>) {
+    let crc = crc32(&data[chunk.start - 4..chunk.end]);
+    data[chunk.end..chunk.end + 4].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// Replaces XMP packets embedded in JPEG, PNG and WebP with spaces,
+/// which XMP allows as padding. XMP of TIFF is stripped with its private tags
+fn strip_xmp(format: ImageFormat, data: &mut [u8]) {
+    match format {
+        ImageFormat::Jpeg => for (marker, payload) in jpeg_segments(data) {
+            let namespace = JPEG_XMP.iter().find(|namespace| data[payload.clone()].starts_with(namespace));
+            if let (APP1, Some(namespace)) = (marker, namespace) {
+                data[payload.start + namespace.len()..payload.end].fill(b' ');
+            }
+        },
+        ImageFormat::Png => for (kind, chunk) in png_chunks(data) {
+            if &kind != b"iTXt" || !data[chunk.clone()].starts_with(PNG_XMP) {
+                continue;
+            }
+            // Keyword is followed by compression flag and method, language and translated keyword
+            let flag = chunk.start + PNG_XMP.len();
+            let text = data.get(flag + 2..chunk.end)
+                .and_then(|rest| rest.iter().enumerate().filter(|(_, byte)| **byte == 0).nth(1))
+                .map(|(index, _)| flag + 2 + index + 1);
+            if let Some(text) = text {
+                // Spaces are not compressed
+                data[flag] = 0;
+                data[text..chunk.end].fill(b' ');
+                update_crc(data, chunk);
+            }
+        },
+        ImageFormat::Webp => for (kind, chunk) in webp_chunks(data) {
+            if &kind == b"XMP " {
+                data[chunk].fill(b' ');
+            }
+        },
+        ImageFormat::Tiff | ImageFormat::Gif | ImageFormat::Bmp => {}
+    }
+}
+
+/// Removes EXIF metadata selected by `strip` from image in `format`,
+/// with XMP packets if everything is stripped.
+/// Data is changed in place and keeps its size.
+/// Metadata embedded in JPEG, PNG and WebP is wiped entirely if it is malformed
+///
+/// Returns false if metadata of TIFF image is malformed and left unchanged
+pub fn strip(format: ImageFormat, data: &mut [u8], strip: Strip) -> bool {
+    if strip == Strip::All {
+        strip_xmp(format, data);
+    }
+    let range = match locate(format, data) {
+        Some(range) if strip != Strip::Nothing => range,
+        _ => return true,
+    };
+    let tiff = &mut data[range.clone()];
+    let stripped = match (strip, format) {
+        (Strip::Gps, _) => strip_tiff(tiff, |tag| tag == GPS_IFD, false),
+        (Strip::All, ImageFormat::Tiff) => strip_tiff(tiff, |tag| PRIVATE_TAGS.contains(&tag), false),
+        (Strip::All, _) => strip_tiff(tiff, |tag| tag != ORIENTATION, true),
+        (Strip::Nothing, _) => Some(()),
+    };
+    if stripped.is_none() {
+        if format == ImageFormat::Tiff {
+            return false;
+        }
+        tiff.iter_mut().for_each(|byte| *byte = 0);
+    }
+    if format == ImageFormat::Png {
+        update_crc(data, range);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: &[u8] = b"<x:xmpmeta><exif:GPSLatitude>51,30N</exif:GPSLatitude></x:xmpmeta>";
+
+    fn stripped(format: ImageFormat, data: &[u8], strip: Strip) -> Vec<u8> {
+        let mut data = data.to_vec();
+        assert!(super::strip(format, &mut data, strip));
+        data
+    }
+
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|window| window == part)
+    }
+
+    fn png_chunk(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = (payload.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(payload);
+        chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn jpeg_xmp_is_stripped_with_everything() {
+        let payload = [JPEG_XMP[0], PACKET].concat();
+        let mut data = vec![0xFF, 0xD8, 0xFF, APP1];
+        data.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]);
+
+        assert_eq!(data, stripped(ImageFormat::Jpeg, &data, Strip::Gps));
+        let stripped = stripped(ImageFormat::Jpeg, &data, Strip::All);
+        assert_eq!(data.len(), stripped.len());
+        assert!(!contains(&stripped, b"GPSLatitude"));
+        assert!(contains(&stripped, JPEG_XMP[0]));
+    }
+
+    #[test]
+    fn compressed_png_xmp_is_replaced_with_spaces() {
+        let payload = [PNG_XMP, &[1, 0], b"en\0\0", PACKET].concat();
+        let data = [&b"\x89PNG\r\n\x1A\n"[..], &png_chunk(b"iTXt", &payload), &png_chunk(b"IEND", b"")].concat();
+
+        let stripped = stripped(ImageFormat::Png, &data, Strip::All);
+
+        let (kind, chunk) = png_chunks(&stripped).remove(0);
+        assert_eq!(b"iTXt", &kind);
+        let text = chunk.start + PNG_XMP.len() + 6;
+        assert_eq!(0, stripped[chunk.start + PNG_XMP.len()]);
+        assert!(stripped[text..chunk.end].iter().all(|byte| *byte == b' '));
+        assert_eq!(&stripped[text - 4..text], b"en\0\0");
+        assert_eq!(crc32(&stripped[chunk.start - 4..chunk.end]).to_be_bytes(), stripped[chunk.end..chunk.end + 4]);
+    }
+
+    #[test]
+    fn webp_xmp_chunk_is_replaced_with_spaces() {
+        let mut data = b"RIFF\0\0\0\0WEBPXMP ".to_vec();
+        data.extend_from_slice(&(PACKET.len() as u32).to_le_bytes());
+        data.extend_from_slice(PACKET);
+
+        let stripped = stripped(ImageFormat::Webp, &data, Strip::All);
+
+        assert_eq!(&data[..20], &stripped[..20]);
+        assert!(stripped[20..].iter().all(|byte| *byte == b' '));
+    }
+}
@@ -0,0 +1,119 @@
+//! Detection of formats of images
+use crate::image::{OutputFormat, INPUT_FORMATS};
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Formats of images accepted by server.
+/// AVIF is not accepted, since neither backend can decode it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    /// Only the first frame of animation is transformed
+    Gif,
+    Webp,
+    Bmp,
+    Tiff,
+}
+
+/// Extensions under which supported images are stored.
+/// Derived from image's format, see ['ImageFormat::extension']
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["jpeg", "png", "gif", "webp", "bmp", "tiff"];
+
+impl ImageFormat {
+    pub const ALL: &'static [ImageFormat] = &[
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+        ImageFormat::Gif,
+        ImageFormat::Webp,
+        ImageFormat::Bmp,
+        ImageFormat::Tiff,
+    ];
+
+    /// Detects format by signature of data
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1A\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else if data.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            Some(ImageFormat::Tiff)
+        } else {
+            None
+        }
+    }
+
+    /// MIME type, like "image/png"
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::Tiff => "image/tiff",
+        }
+    }
+
+    /// Subtype of MIME type, used as extension of stored files
+    pub fn extension(&self) -> &'static str {
+        &self.mime_type()["image/".len()..]
+    }
+
+    /// Checks whether enabled backend can decode this format, see ['INPUT_FORMATS']
+    pub fn is_supported(&self) -> bool {
+        INPUT_FORMATS.contains(self)
+    }
+
+    /// The same format as a result of transformation, if images can be encoded to it
+    pub fn output_format(&self) -> Option<OutputFormat> {
+        match self {
+            ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
+            ImageFormat::Png => Some(OutputFormat::Png),
+            ImageFormat::Webp => Some(OutputFormat::Webp),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "gif" => Ok(ImageFormat::Gif),
+            "webp" => Ok(ImageFormat::Webp),
+            "bmp" => Ok(ImageFormat::Bmp),
+            "tiff" | "tif" => Ok(ImageFormat::Tiff),
+            other => Err(format!("{} is not a supported format", other)),
+        }
+    }
+}
+
+impl From<OutputFormat> for ImageFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::Webp,
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
@@ -0,0 +1,243 @@
+//! Reading of images' headers, without decoding pixels
+use crate::image::{ColourType, ImageFormat};
+
+/// Properties of image red from its header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub colour_type: ColourType,
+    /// Bits per sample, or per index of indexed images
+    pub bit_depth: u8,
+    /// Progressive jpeg or interlaced png and gif
+    pub progressive: bool,
+}
+
+/// Reads header of image in `format`.
+/// Returns None if header is truncated or malformed
+pub fn read(format: ImageFormat, data: &[u8]) -> Option<Header> {
+    match format {
+        ImageFormat::Jpeg => jpeg_header(data),
+        ImageFormat::Png => png_header(data),
+        ImageFormat::Gif => gif_header(data),
+        ImageFormat::Webp => webp_header(data),
+        ImageFormat::Bmp => bmp_header(data),
+        ImageFormat::Tiff => tiff_header(data),
+    }
+}
+
+pub(crate) fn u16_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+    Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+}
+
+fn u24_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes([*data.get(offset)?, *data.get(offset + 1)?, *data.get(offset + 2)?, 0]))
+}
+
+pub(crate) fn u32_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let bytes = [*data.get(offset)?, *data.get(offset + 1)?, *data.get(offset + 2)?, *data.get(offset + 3)?];
+    Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+}
+
+/// Finds the first start of frame segment
+fn jpeg_header(data: &[u8]) -> Option<Header> {
+    let mut offset = 2;
+    loop {
+        if *data.get(offset)? != 0xFF {
+            return None;
+        }
+        // Markers may be preceded by any number of fill bytes
+        while *data.get(offset + 1)? == 0xFF {
+            offset += 1;
+        }
+        let marker = *data.get(offset + 1)?;
+        match marker {
+            0x01 | 0xD0..=0xD7 => offset += 2,
+            // Start of scan or end of image before frame
+            0xD9 | 0xDA => return None,
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                let colour_type = match *data.get(offset + 9)? {
+                    1 => ColourType::Grayscale,
+                    3 => ColourType::Rgb,
+                    4 => ColourType::Cmyk,
+                    _ => return None,
+                };
+                return Some(Header {
+                    width: u16_at(data, offset + 7, true)? as u32,
+                    height: u16_at(data, offset + 5, true)? as u32,
+                    colour_type,
+                    bit_depth: *data.get(offset + 4)?,
+                    progressive: [0xC2, 0xC6, 0xCA, 0xCE].contains(&marker),
+                });
+            }
+            _ => offset += 2 + u16_at(data, offset + 2, true)? as usize,
+        }
+    }
+}
+
+/// Reads IHDR chunk, which is always the first one
+fn png_header(data: &[u8]) -> Option<Header> {
+    if data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let colour_type = match *data.get(25)? {
+        0 => ColourType::Grayscale,
+        2 => ColourType::Rgb,
+        3 => ColourType::Indexed,
+        4 => ColourType::GrayscaleAlpha,
+        6 => ColourType::Rgba,
+        _ => return None,
+    };
+    Some(Header {
+        width: u32_at(data, 16, true)?,
+        height: u32_at(data, 20, true)?,
+        colour_type,
+        bit_depth: *data.get(24)?,
+        progressive: *data.get(28)? == 1,
+    })
+}
+
+/// Reads logical screen descriptor and descriptor of the first frame
+fn gif_header(data: &[u8]) -> Option<Header> {
+    const COLOUR_TABLE: u8 = 0x80;
+    const INTERLACED: u8 = 0x40;
+
+    let flags = *data.get(10)?;
+    let table_bits = |flags: u8| (flags & 0x07) + 1;
+    let mut offset = 13;
+    if flags & COLOUR_TABLE != 0 {
+        offset += 3 << table_bits(flags);
+    }
+    // Extensions, like comments and frame's transparency, precede the first frame
+    while *data.get(offset)? == 0x21 {
+        offset += 2;
+        loop {
+            let size = *data.get(offset)? as usize;
+            offset += 1 + size;
+            if size == 0 {
+                break;
+            }
+        }
+    }
+    if *data.get(offset)? != 0x2C {
+        return None;
+    }
+    let frame_flags = *data.get(offset + 9)?;
+    let bit_depth = if frame_flags & COLOUR_TABLE != 0 {
+        table_bits(frame_flags)
+    } else {
+        table_bits(flags)
+    };
+    Some(Header {
+        width: u16_at(data, 6, false)? as u32,
+        height: u16_at(data, 8, false)? as u32,
+        colour_type: ColourType::Indexed,
+        bit_depth,
+        progressive: frame_flags & INTERLACED != 0,
+    })
+}
+
+/// Reads the first chunk: lossy "VP8 ", lossless "VP8L" or extended "VP8X"
+fn webp_header(data: &[u8]) -> Option<Header> {
+    let (width, height, alpha) = match data.get(12..16)? {
+        b"VP8 " => {
+            if data.get(23..26)? != [0x9D, 0x01, 0x2A] {
+                return None;
+            }
+            (u16_at(data, 26, false)? as u32 & 0x3FFF, u16_at(data, 28, false)? as u32 & 0x3FFF, false)
+        }
+        b"VP8L" => {
+            if *data.get(20)? != 0x2F {
+                return None;
+            }
+            let bits = u32_at(data, 21, false)?;
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, bits & (1 << 28) != 0)
+        }
+        b"VP8X" => (u24_at(data, 24)? + 1, u24_at(data, 27)? + 1, *data.get(20)? & 0x10 != 0),
+        _ => return None,
+    };
+    Some(Header {
+        width,
+        height,
+        colour_type: if alpha { ColourType::Rgba } else { ColourType::Rgb },
+        bit_depth: 8,
+        progressive: false,
+    })
+}
+
+/// Reads BITMAPCOREHEADER or BITMAPINFOHEADER and its successors.
+/// Height is negative for top-down bitmaps
+fn bmp_header(data: &[u8]) -> Option<Header> {
+    let header_size = u32_at(data, 14, false)?;
+    let (width, height, bits) = if header_size == 12 {
+        (u16_at(data, 18, false)? as u32, u16_at(data, 20, false)? as u32, u16_at(data, 24, false)?)
+    } else {
+        let width = u32_at(data, 18, false)? as i32;
+        let height = u32_at(data, 22, false)? as i32;
+        (width.checked_abs()? as u32, height.checked_abs()? as u32, u16_at(data, 28, false)?)
+    };
+    let (colour_type, bit_depth) = match bits {
+        1 | 2 | 4 | 8 => (ColourType::Indexed, bits as u8),
+        16 => (ColourType::Rgb, 5),
+        24 => (ColourType::Rgb, 8),
+        32 => (ColourType::Rgba, 8),
+        _ => return None,
+    };
+    Some(Header { width, height, colour_type, bit_depth, progressive: false })
+}
+
+/// Reads tags of the first image file directory
+fn tiff_header(data: &[u8]) -> Option<Header> {
+    const IMAGE_WIDTH: u16 = 256;
+    const IMAGE_LENGTH: u16 = 257;
+    const BITS_PER_SAMPLE: u16 = 258;
+    const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+    const SAMPLES_PER_PIXEL: u16 = 277;
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+
+    let big_endian = data.starts_with(b"MM");
+    let directory = u32_at(data, 4, big_endian)? as usize;
+    let entries = u16_at(data, directory, big_endian)? as usize;
+    let (mut width, mut height) = (None, None);
+    let (mut bits, mut photometric, mut samples) = (1, None, 1);
+    for index in 0..entries {
+        let entry = directory + 2 + index * 12;
+        let tag = u16_at(data, entry, big_endian)?;
+        let count = u32_at(data, entry + 4, big_endian)?;
+        // Values which do not fit into the entry are stored at offset,
+        // only the first one is used
+        let value = match u16_at(data, entry + 2, big_endian)? {
+            SHORT if count > 2 => u16_at(data, u32_at(data, entry + 8, big_endian)? as usize, big_endian)? as u32,
+            SHORT => u16_at(data, entry + 8, big_endian)? as u32,
+            LONG => u32_at(data, entry + 8, big_endian)?,
+            _ => continue,
+        };
+        match tag {
+            IMAGE_WIDTH => width = Some(value),
+            IMAGE_LENGTH => height = Some(value),
+            BITS_PER_SAMPLE => bits = value,
+            PHOTOMETRIC_INTERPRETATION => photometric = Some(value),
+            SAMPLES_PER_PIXEL => samples = value,
+            _ => {}
+        }
+    }
+    // Missing interpretation is guessed by samples, extra samples are alpha
+    let photometric = photometric.unwrap_or(if samples < 3 { 1 } else { 2 });
+    let colour_type = match photometric {
+        0 | 1 if samples > 1 => ColourType::GrayscaleAlpha,
+        0 | 1 => ColourType::Grayscale,
+        3 => ColourType::Indexed,
+        5 => ColourType::Cmyk,
+        _ if samples > 3 => ColourType::Rgba,
+        _ => ColourType::Rgb,
+    };
+    Some(Header {
+        width: width?,
+        height: height?,
+        colour_type,
+        bit_depth: bits.min(u8::MAX as u32) as u8,
+        progressive: false,
+    })
+}
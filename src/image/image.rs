@@ -1,7 +1,11 @@
-use crate::image::transformation;
+// failure's derive implements traits inside of a constant
+#![allow(non_local_definitions)]
+use crate::image::transformation::{self, Encoding, Fit};
+use crate::image::{exif, ImageFormat, ImageMetadata, ImageName, Limits, Source, Strip};
 
 use serde::{Deserialize, Serialize};
-use std::io::Write;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
 use failure::Fail;
 
 /// The type of data that the server collects.
@@ -15,61 +19,153 @@ pub enum ImageError {
     PreviewGeneration,
     #[fail(display = "Unsupported image format")]
     UnsupportedImageFormat,
+    #[fail(display = "Image transformation failed")]
+    Transformation,
+    #[fail(display = "Invalid image name: {}", _0)]
+    InvalidName(String),
+    #[fail(display = "Image is larger than {} bytes", _0)]
+    TooLarge(usize),
+    #[fail(display = "Image dimensions exceed limits: {}", _0)]
+    DimensionsExceeded(String),
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Image {
-    name: String,
+    name: ImageName,
     binary_data: Vec<u8>,
+    /// Red once, when image is created
+    metadata: ImageMetadata,
 }
 
 impl Image {
-    /// Constructs a new Image from name and binary data
+    /// Constructs a new uploaded Image from name and binary data.
+    /// Time of upload and `source` are recorded in its metadata
     ///
     /// # Errors
+    /// If name is not valid, see ['ImageName::new']
     /// If image's format is unknown
     ///
-    pub fn create(name: String, binary_data: Vec<u8>) -> Result<Self, ImageError> {
-        if !Self::is_supported_type(&binary_data) {
-            return Err(ImageError::UnsupportedImageFormat);
-        }
+    pub fn create(name: String, binary_data: Vec<u8>, source: Source) -> Result<Self, ImageError> {
+        let mut image = Self::new(ImageName::new(&name)?, binary_data)?;
+        let uploaded = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        image.metadata.uploaded = Some(uploaded);
+        image.metadata.source = Some(source);
+        Ok(image)
+    }
+
+    /// Constructs a new Image from already validated name and binary data
+    ///
+    /// # Errors
+    /// If image's format is unknown
+    ///
+    pub fn new(name: ImageName, binary_data: Vec<u8>) -> Result<Self, ImageError> {
+        let metadata = ImageMetadata::read(&binary_data)?;
         Ok(Image {
             name,
             binary_data,
+            metadata,
         })
     }
 
-    /// Creates 100x100 jpg preview of Image
+    /// Creates 100x100 preview of Image, encoded by `encoding`.
+    /// Image is scaled preserving aspect ratio and centered excess is cropped
     ///
     /// # Errors
-    /// If binary_data field cannot be red as image by opencv
+    /// If binary_data field cannot be red as image by the backend
     ///
-    pub fn generate_preview(&self) -> Result<Self, ImageError> {
-        let preview_data = transformation::resize_image(&self.binary_data, 100, 100)
+    pub fn generate_preview(&self, encoding: Encoding) -> Result<Self, ImageError> {
+        let preview_data = transformation::transform_image(&self.binary_data, 100, 100, Fit::Cover, encoding,
+                                                           self.metadata.orientation)
             .ok_or(ImageError::PreviewGeneration)?;
-        Ok(Image::create("preview_".to_string() + &self.name, preview_data)?)
+        Image::new(self.name.prefixed("preview_"), preview_data)
+    }
+
+    /// Creates rendition of Image with given width, encoded by `encoding`.
+    /// Aspect ratio of displayed image is preserved, image is never upscaled
+    ///
+    /// # Errors
+    /// If binary_data field cannot be red as image by the backend
+    ///
+    pub fn generate_rendition(&self, width: u32, encoding: Encoding) -> Result<Self, ImageError> {
+        let (original_width, original_height) = self.dimensions();
+        let original_width = original_width.max(1) as u64;
+        let width = (width as u64).min(original_width).max(1);
+        let height = ((original_height as u64 * width + original_width / 2) / original_width).max(1);
+        let rendition_data = transformation::transform_image(&self.binary_data, width as usize, height as usize,
+                                                             Fit::Fill, encoding, self.metadata.orientation)
+            .ok_or(ImageError::PreviewGeneration)?;
+        Image::new(self.name.clone(), rendition_data)
+    }
+
+
+    /// Creates image fitted to width x height by `fit` and encoded by `encoding`.
+    /// Like preview and renditions, it is rotated by EXIF orientation and has no metadata
+    ///
+    /// # Errors
+    /// If binary_data field cannot be red as image by the backend
+    ///
+    pub fn transform(&self, width: u32, height: u32, fit: Fit, encoding: Encoding) -> Result<Self, ImageError> {
+        let data = transformation::transform_image(&self.binary_data, width as usize, height as usize, fit, encoding,
+                                                   self.metadata.orientation)
+            .ok_or(ImageError::Transformation)?;
+        Image::new(self.name.clone(), data)
     }
 
 
+    /// Checks size and dimensions of Image, red from its header, against `limits`.
+    /// Must pass before Image is transformed, since transformations decode all pixels
+    ///
+    /// # Errors
+    /// See ['Limits::check']
+    ///
+    pub fn check_limits(&self, limits: &Limits) -> Result<(), ImageError> {
+        limits.check(&self.metadata)
+    }
+
+    /// Removes EXIF metadata selected by `strip` from binary data.
+    /// Orientation is kept, so image is displayed the same way
+    pub fn strip_exif(&mut self, strip: Strip) {
+        if !exif::strip(self.metadata.format, &mut self.binary_data, strip) {
+            warn!("Malformed EXIF metadata of {} is not stripped", self.name);
+        }
+        if strip == Strip::All {
+            self.metadata.camera = None;
+            self.metadata.captured = None;
+        }
+    }
+
     pub fn name(&self) -> &str {
-        &self.name
+        self.name.as_str()
+    }
+    pub fn rename(&mut self, name: ImageName) {
+        self.name = name;
     }
     pub fn data(&self) -> &Vec<u8> {
         &self.binary_data
     }
+    /// Hex encoded SHA-256 digest of binary data
+    pub fn digest(&self) -> String {
+        format!("{:x}", Sha256::digest(&self.binary_data))
+    }
 
-    fn is_supported_type(data: &[u8]) -> bool {
-        match immeta::load_from_buf(data) {
-            Ok(immeta::GenericMetadata::Jpeg(_)) |
-            Ok(immeta::GenericMetadata::Png(_)) => true,
-            _ => false,
-        }
+    pub fn metadata(&self) -> &ImageMetadata {
+        &self.metadata
+    }
+    pub fn format(&self) -> ImageFormat {
+        self.metadata.format
+    }
+    /// Width and height of image in pixels
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.metadata.width, self.metadata.height)
+    }
+    pub fn mime_type(&self) -> &'static str {
+        self.metadata.format.mime_type()
     }
     pub fn extension(&self) -> &str {
-        immeta::load_from_buf(self.data()).unwrap()
-            .mime_type()
-            .split('/')
-            .collect::<Vec<_>>()[1]
+        self.metadata.format.extension()
     }
 }
 
@@ -0,0 +1,51 @@
+//! Limits of accepted images, checked against header before pixels are decoded
+use crate::image::{ImageError, ImageMetadata};
+
+/// Largest image, which may be decoded without exhausting memory.
+/// Decoded image takes up to 4 bytes per pixel regardless of its compressed size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Width multiplied by height
+    pub max_pixels: u64,
+    /// Size of encoded image
+    pub max_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 50_000_000,
+            max_bytes: 20 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks image described by `metadata`
+    ///
+    /// # Errors
+    /// ['ImageError::TooLarge'] if image has more bytes than allowed
+    /// ['ImageError::DimensionsExceeded'] if width, height or number of pixels is exceeded
+    ///
+    pub fn check(&self, metadata: &ImageMetadata) -> Result<(), ImageError> {
+        if metadata.byte_size > self.max_bytes {
+            return Err(ImageError::TooLarge(self.max_bytes));
+        }
+        let exceeded = |reason: String| Err(ImageError::DimensionsExceeded(reason));
+        if metadata.width > self.max_width {
+            return exceeded(format!("width {} is larger than {}", metadata.width, self.max_width));
+        }
+        if metadata.height > self.max_height {
+            return exceeded(format!("height {} is larger than {}", metadata.height, self.max_height));
+        }
+        let pixels = metadata.width as u64 * metadata.height as u64;
+        if pixels > self.max_pixels {
+            return exceeded(format!("{} pixels are more than {}", pixels, self.max_pixels));
+        }
+        Ok(())
+    }
+}
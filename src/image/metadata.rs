@@ -0,0 +1,96 @@
+//! Description of images, computed once when image is created
+use crate::image::{exif, header, ImageError, ImageFormat, Orientation};
+
+use serde::{Deserialize, Serialize};
+
+/// Colour model of pixels
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColourType {
+    Grayscale,
+    GrayscaleAlpha,
+    Rgb,
+    Rgba,
+    /// Pixels are indices of palette
+    Indexed,
+    Cmyk,
+}
+
+/// How image was uploaded
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Source {
+    /// Base64 encoded in JSON request
+    Json,
+    /// Downloaded from `url`, as requested before redirects
+    Url { url: String },
+    /// File of multipart request
+    Multipart,
+}
+
+/// Properties of image, stored alongside it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImageMetadata {
+    pub format: ImageFormat,
+    pub mime_type: String,
+    /// Width and height of displayed image, after EXIF orientation is applied
+    pub width: u32,
+    pub height: u32,
+    /// EXIF orientation of stored pixels
+    #[serde(default)]
+    pub orientation: Orientation,
+    pub colour_type: ColourType,
+    /// Bits per sample, or per index of indexed images
+    pub bit_depth: u8,
+    /// Progressive jpeg or interlaced png and gif
+    pub progressive: bool,
+    pub byte_size: usize,
+    /// Make and model of camera from EXIF
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+    /// Time when photo was taken from EXIF, like "2020-05-01T12:00:00", without time zone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured: Option<String>,
+    /// Unix timestamp in seconds. Only uploaded images have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploaded: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+}
+
+impl ImageMetadata {
+    /// Reads metadata from header of image
+    ///
+    /// # Errors
+    /// If image's format is unknown
+    /// If header cannot be red
+    ///
+    pub fn read(data: &[u8]) -> Result<Self, ImageError> {
+        let format = ImageFormat::detect(data)
+            .ok_or(ImageError::UnsupportedImageFormat)?;
+        let header = header::read(format, data)
+            .ok_or(ImageError::UnsupportedImageFormat)?;
+        let exif = exif::read(format, data);
+        let orientation = exif.orientation.unwrap_or_default();
+        let (width, height) = if orientation.is_transposed() {
+            (header.height, header.width)
+        } else {
+            (header.width, header.height)
+        };
+        Ok(ImageMetadata {
+            format,
+            mime_type: format.mime_type().to_string(),
+            width,
+            height,
+            orientation,
+            colour_type: header.colour_type,
+            bit_depth: header.bit_depth,
+            progressive: header.progressive,
+            byte_size: data.len(),
+            camera: exif.camera,
+            captured: exif.captured,
+            uploaded: None,
+            source: None,
+        })
+    }
+}
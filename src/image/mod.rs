@@ -1,4 +1,17 @@
 mod transformation;
+#[allow(clippy::module_inception)]
 mod image;
+mod name;
+mod format;
+mod header;
+mod metadata;
+mod exif;
+mod limits;
 
 pub use image::*;
+pub use name::{ImageName, MAX_NAME_LENGTH};
+pub use format::{ImageFormat, SUPPORTED_EXTENSIONS};
+pub use metadata::{ColourType, ImageMetadata, Source};
+pub use exif::Strip;
+pub use limits::Limits;
+pub use transformation::{Encoding, Fit, Orientation, OutputFormat, Rgb, INPUT_FORMATS, OUTPUT_FORMATS};
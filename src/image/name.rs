@@ -0,0 +1,89 @@
+//! Names of images, safe to be used as file names
+use crate::image::ImageError;
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Limit of name's length in bytes.
+/// Leaves room for prefixes, suffixes and extensions of stored files
+/// within usual limit of 255 bytes
+pub const MAX_NAME_LENGTH: usize = 200;
+
+/// Names of devices on Windows, which cannot be used as file names
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Validated name of image.
+/// Name is normalised to unicode NFC form without surrounding whitespace,
+/// so the same name typed differently refers to the same image.
+/// Deserialized names are validated too
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct ImageName(String);
+
+impl ImageName {
+    /// Normalises and validates name
+    ///
+    /// # Errors
+    /// If name is empty or longer than ['MAX_NAME_LENGTH']
+    /// If name contains path separators or control characters
+    /// If name is hidden or reserved file name
+    ///
+    pub fn new(name: &str) -> Result<Self, ImageError> {
+        let name: String = name.trim().nfc().collect();
+        let invalid = |reason: String| Err(ImageError::InvalidName(reason));
+
+        if name.is_empty() {
+            return invalid("name is empty".to_string());
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return invalid(format!("name is longer than {} bytes", MAX_NAME_LENGTH));
+        }
+        if let Some(separator) = name.chars().find(|c| *c == '/' || *c == '\\') {
+            return invalid(format!("name contains path separator '{}'", separator));
+        }
+        if let Some(control) = name.chars().find(|c| c.is_control()) {
+            return invalid(format!("name contains control character U+{:04X}", control as u32));
+        }
+        if name.starts_with('.') {
+            return invalid("name must not start with a dot".to_string());
+        }
+        if name.ends_with('.') {
+            return invalid("name must not end with a dot".to_string());
+        }
+        let device = name.split('.').next().unwrap_or("").trim_end().to_uppercase();
+        if RESERVED_NAMES.contains(&device.as_str()) {
+            return invalid(format!("{} is a reserved name", device));
+        }
+        Ok(ImageName(name))
+    }
+
+    /// Name of image derived from image with this name
+    pub(crate) fn prefixed(&self, prefix: &str) -> Self {
+        ImageName(format!("{}{}", prefix, self.0))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for ImageName {
+    type Error = ImageError;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        ImageName::new(&name)
+    }
+}
+
+impl fmt::Display for ImageName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -1,44 +1,129 @@
-//! Implementation of resizing image with opencv FFI
-use std::slice;
-use std::ffi::c_void;
-
-/// Extends Vec<u8> given as *mut c_void from C-like array, given as *mut u8 pointer and usize
-/// used as callback from C/C++ code
-extern "C" fn vec_extend_from_c_array(dst: *mut c_void, src_data: *mut u8, src_size: usize) {
-    // Update the value in RustObject with the value received from the callback
-    unsafe {
-        let input = slice::from_raw_parts(src_data, src_size);
-        (&mut *(dst as *mut Vec<u8>)).extend_from_slice(input);
-    }
-}
-
-
-#[link(name = "opencv_resize")]
-extern {
-    /// Registration of output data and callback which store data
-    fn register_output(output: *mut c_void,
-                       store_function: extern fn(*mut c_void, *mut u8, usize)) -> i32;
-    /// Resize image given as in_data with in_size to num_rows x num_cols jpg image
-    /// Store result to registered output by store_function
-    // It cannot store data to given in signature output,
-    // because size of result array is not known before inference
-    // That's why callback is used.
-    fn resize(in_data: *const u8, in_size: i32, num_rows: i32, num_cols: i32) -> i32;
-}
-
-/// Safe function of getting data of resized image.
-/// Returns binary data of 100x100 jpg image in ['Option']
-///
-/// # Errors
-/// If data cannot be represented like image by OpenCV
-///
-pub fn resize_image(data: &Vec<u8>, cols: usize, rows: usize) -> Option<Vec<u8>> {
-    let mut result: Vec<u8> = vec![];
-    unsafe {
-        assert_eq!(0, register_output(&mut result as *mut _ as *mut c_void, vec_extend_from_c_array));
-        if resize(data.as_ptr(), data.len() as _, rows as _, cols as _) != 0 {
-            return None;
+//! Resizing and encoding of images
+//! Backend is selected by cargo features:
+//! "opencv" (default) uses FFI to OpenCV, "pure-rust" uses image crate
+use crate::image::ImageFormat;
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "opencv")]
+mod opencv;
+#[cfg(feature = "opencv")]
+pub use opencv::{transform_image, INPUT_FORMATS, OUTPUT_FORMATS};
+
+#[cfg(all(feature = "pure-rust", not(feature = "opencv")))]
+mod pure_rust;
+#[cfg(all(feature = "pure-rust", not(feature = "opencv")))]
+pub use pure_rust::{transform_image, INPUT_FORMATS, OUTPUT_FORMATS};
+
+#[cfg(not(any(feature = "opencv", feature = "pure-rust")))]
+compile_error!("One of transformation backends must be enabled: \"opencv\" or \"pure-rust\"");
+
+/// Colour given by red, green and blue components
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+/// How image is fitted into requested size
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Fit {
+    /// Stretch to exact size, aspect ratio is not preserved
+    Fill,
+    /// Fit inside requested size preserving aspect ratio,
+    /// so one side of result may be smaller
+    Contain,
+    /// Fill requested size preserving aspect ratio, centered excess is cropped
+    Cover,
+    /// Fit inside requested size preserving aspect ratio
+    /// and pad to exact size with background colour
+    Pad(Rgb),
+}
+
+/// EXIF orientation of stored pixels, from 1 to 8.
+/// Transformations rotate and flip images by it, so that results are upright
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Orientation(pub u8);
+
+impl Orientation {
+    pub const NORMAL: Orientation = Orientation(1);
+
+    /// Checks whether width and height of displayed image are swapped
+    pub fn is_transposed(&self) -> bool {
+        matches!(self.0, 5..=8)
+    }
+}
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Orientation::NORMAL
+    }
+}
+
+/// Format of transformation's result.
+/// Formats supported by enabled backend are listed in ['OUTPUT_FORMATS']
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    /// Keeps transparency
+    Png,
+    /// Keeps transparency
+    Webp,
+}
+
+impl OutputFormat {
+    /// MIME type of the same ['ImageFormat']
+    pub fn mime_type(&self) -> &'static str {
+        ImageFormat::from(*self).mime_type()
+    }
+
+    /// Extension of the same ['ImageFormat']
+    pub fn extension(&self) -> &'static str {
+        ImageFormat::from(*self).extension()
+    }
+
+    /// Checks whether enabled backend can encode this format
+    pub fn is_supported(&self) -> bool {
+        OUTPUT_FORMATS.contains(self)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::Webp),
+            other => Err(format!("{} is not an output format", other)),
         }
     }
-    Some(result)
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// Format of transformation's result with its quality
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Encoding {
+    pub format: OutputFormat,
+    /// Quality from 1 to 100 for jpeg and webp, compression level from 0 to 9 for png
+    pub quality: u8,
+}
+
+impl Encoding {
+    /// Default quality of backends
+    pub fn new(format: OutputFormat) -> Self {
+        let quality = match format {
+            OutputFormat::Jpeg => 95,
+            OutputFormat::Png => 3,
+            OutputFormat::Webp => 90,
+        };
+        Encoding { format, quality }
+    }
 }
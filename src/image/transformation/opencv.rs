@@ -0,0 +1,90 @@
+//! Implementation of resizing image with opencv FFI
+use super::{Encoding, Fit, Orientation, OutputFormat, Rgb};
+use crate::image::ImageFormat;
+use std::slice;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+
+/// Formats decoded by every supported version of OpenCV.
+/// GIF is decoded only since OpenCV 4.7
+pub const INPUT_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Webp, ImageFormat::Bmp, ImageFormat::Tiff,
+];
+
+/// Formats encoded by OpenCV
+pub const OUTPUT_FORMATS: &[OutputFormat] = &[OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp];
+
+/// Code of fit mode, known by C++ code
+fn fit_code(fit: Fit) -> i32 {
+    match fit {
+        Fit::Fill => 0,
+        Fit::Contain => 1,
+        Fit::Cover => 2,
+        Fit::Pad(_) => 3,
+    }
+}
+
+/// Background colour of fit mode packed as 0xRRGGBB
+fn background(fit: Fit) -> i32 {
+    match fit {
+        Fit::Pad(Rgb(red, green, blue)) => (red as i32) << 16 | (green as i32) << 8 | blue as i32,
+        _ => 0,
+    }
+}
+
+/// Extension of format, known by OpenCV
+fn extension(format: OutputFormat) -> &'static CStr {
+    let extension: &'static [u8] = match format {
+        OutputFormat::Jpeg => b".jpg\0",
+        OutputFormat::Png => b".png\0",
+        OutputFormat::Webp => b".webp\0",
+    };
+    CStr::from_bytes_with_nul(extension).unwrap()
+}
+
+/// Extends Vec<u8> given as *mut c_void from C-like array, given as *mut u8 pointer and usize
+/// used as callback from C/C++ code
+extern "C" fn vec_extend_from_c_array(dst: *mut c_void, src_data: *mut u8, src_size: usize) {
+    // Update the value in RustObject with the value received from the callback
+    unsafe {
+        let input = slice::from_raw_parts(src_data, src_size);
+        (&mut *(dst as *mut Vec<u8>)).extend_from_slice(input);
+    }
+}
+
+
+#[link(name = "opencv_resize")]
+extern {
+    /// Rotate image given as in_data with in_size by EXIF orientation,
+    /// resize it to num_rows x num_cols with fit mode
+    /// and encode it to format given by null-terminated extension like ".png" with quality
+    /// background is 0xRRGGBB colour of padding
+    /// Store result to output by store_function
+    // It cannot store data to given in signature output,
+    // because size of result array is not known before inference
+    // That's why callback is used.
+    // Output is passed with every call, so concurrent calls don't share any state.
+    fn transform(in_data: *const u8, in_size: i32, num_rows: i32, num_cols: i32,
+                 orientation: i32, fit: i32, background: i32, extension: *const c_char, quality: i32,
+                 output: *mut c_void, store_function: extern fn(*mut c_void, *mut u8, usize)) -> i32;
+}
+
+/// Safe function of getting data of transformed image.
+/// Returns binary data of image, rotated by `orientation`,
+/// fitted to cols x rows by `fit` and encoded by `encoding`
+///
+/// # Errors
+/// If data cannot be represented like image by OpenCV
+///
+pub fn transform_image(data: &[u8], cols: usize, rows: usize, fit: Fit, encoding: Encoding,
+                       orientation: Orientation) -> Option<Vec<u8>> {
+    let mut result: Vec<u8> = vec![];
+    unsafe {
+        if transform(data.as_ptr(), data.len() as _, rows as _, cols as _, orientation.0 as _,
+                     fit_code(fit), background(fit), extension(encoding.format).as_ptr(), encoding.quality as _,
+                     &mut result as *mut _ as *mut c_void, vec_extend_from_c_array) != 0 {
+            return None;
+        }
+    }
+    Some(result)
+}
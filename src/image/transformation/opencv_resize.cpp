@@ -1,55 +1,158 @@
 //! C++ code that uses opencv functions for load image data,
-//! resize it and store by rust_callback to out_ptr
+//! resize it and store by rust_callback to output.
+//! There is no global state, so functions are safe to call from several threads
+//! Input may be any format decoded by imgcodecs: JPEG, PNG, WebP, BMP, TIFF
+//! and, since OpenCV 4.7, GIF (its first frame is decoded)
 #include <stdio.h>
 #include <vector>
 #include <inttypes.h>
+#include <algorithm>
+#include <string>
 
 #include <opencv2/opencv.hpp>
 #include <opencv2/imgcodecs.hpp>
 
 
+// Rotates and flips src by EXIF orientation 1-8, so that it is upright.
+// Rotation cannot be done in place for not square images
+static void orient(cv::Mat &src, int32_t orientation) {
+    cv::Mat rotated;
+    switch (orientation) {
+        case 2:
+            cv::flip(src, src, 1);
+            break;
+        case 3:
+            cv::rotate(src, rotated, cv::ROTATE_180);
+            src = rotated;
+            break;
+        case 4:
+            cv::flip(src, src, 0);
+            break;
+        case 5:
+            cv::rotate(src, rotated, cv::ROTATE_90_CLOCKWISE);
+            cv::flip(rotated, src, 1);
+            break;
+        case 6:
+            cv::rotate(src, rotated, cv::ROTATE_90_CLOCKWISE);
+            src = rotated;
+            break;
+        case 7:
+            cv::rotate(src, rotated, cv::ROTATE_90_COUNTERCLOCKWISE);
+            cv::flip(rotated, src, 1);
+            break;
+        case 8:
+            cv::rotate(src, rotated, cv::ROTATE_90_COUNTERCLOCKWISE);
+            src = rotated;
+            break;
+        default:
+            break;
+    }
+}
+
 extern "C" {
 
 typedef void (*rust_callback)(void * /* rust Vec*/, void * /*cpp vector data*/, size_t /*cpp vector size*/);
-void *out_ptr = nullptr;
-rust_callback store = nullptr;
 
-int32_t register_output(void *output, rust_callback store_function) {
+// orientation: EXIF orientation 1-8 of input, applied before resizing.
+//              Unchanged decoding of imgcodecs ignores it
+// fit: 0 - stretch to exact num_rows x num_cols
+//      1 - fit inside num_rows x num_cols preserving aspect ratio
+//      2 - fill num_rows x num_cols preserving aspect ratio, centered excess is cropped
+//      3 - fit inside num_rows x num_cols preserving aspect ratio, pad to exact size with background
+// background: 0xRRGGBB colour of padding
+// ext: extension of output format, like ".jpg", ".png" or ".webp"
+// quality: 1-100 for ".jpg" and ".webp", compression level 0-9 for ".png"
+// output: result's destination, passed to store_function with encoded data
+int32_t transform(void *in_ptr, int32_t in_size, int32_t num_rows, int32_t num_cols,
+                  int32_t orientation, int32_t fit, int32_t background, const char *ext, int32_t quality,
+                  void *output, rust_callback store_function) {
+//  invalid output
     if (output == nullptr || store_function == nullptr) {
         return -1;
     }
-    out_ptr = output;
-    store = store_function;
-    return 0;
-}
-
-int32_t resize(void *in_ptr, int32_t in_size, int32_t num_rows, int32_t num_cols) {
-//  unregistered output
-    if (out_ptr == nullptr || store == nullptr) {
-        return -1;
-    }
 //  invalid input
-    if (in_ptr == nullptr || in_size <= 0 || num_rows <= 0 || num_cols <= 0) {
+    if (in_ptr == nullptr || in_size <= 0 || num_rows <= 0 || num_cols <= 0 || ext == nullptr) {
         return -2;
     }
 
     cv::Mat in_m{1, in_size, CV_8UC1, in_ptr};
     cv::InputArray in_a{in_m};
 
+    std::string extension{ext};
+    bool alpha = extension == ".png" || extension == ".webp";
+    std::vector<int> params{};
+    if (extension == ".jpg") {
+        params = {cv::IMWRITE_JPEG_QUALITY, quality};
+    } else if (extension == ".webp") {
+        params = {cv::IMWRITE_WEBP_QUALITY, quality};
+    } else if (extension == ".png") {
+        params = {cv::IMWRITE_PNG_COMPRESSION, quality};
+    }
+
     try {
-        auto src = cv::imdecode(in_a, cv::IMREAD_COLOR);
+        // Unchanged image keeps its alpha channel
+        auto src = cv::imdecode(in_a, cv::IMREAD_UNCHANGED);
 
         if (src.data == nullptr || src.size().empty()) {
             return -3;
         }
-        cv::Mat dst_m{num_rows, num_cols, CV_8UC1, cv::Scalar(0.)};
+        orient(src, orientation);
+        if (src.depth() == CV_16U) {
+            src.convertTo(src, CV_8U, 1.0 / 256);
+        } else if (src.depth() != CV_8U) {
+            src.convertTo(src, CV_8U);
+        }
+        if (src.channels() == 1) {
+            cv::cvtColor(src, src, cv::COLOR_GRAY2BGR);
+        } else if (src.channels() == 4 && !alpha) {
+            cv::cvtColor(src, src, cv::COLOR_BGRA2BGR);
+        }
+
+        double scale_cols = static_cast<double>(num_cols) / src.cols;
+        double scale_rows = static_cast<double>(num_rows) / src.rows;
+        cv::Mat dst;
+        switch (fit) {
+            case 0:
+                cv::resize(src, dst, cv::Size{num_cols, num_rows});
+                break;
+            case 1:
+            case 3: {
+                double scale = std::min(scale_cols, scale_rows);
+                cv::Size size{std::min(num_cols, std::max(1, static_cast<int>(src.cols * scale + 0.5))),
+                              std::min(num_rows, std::max(1, static_cast<int>(src.rows * scale + 0.5)))};
+                cv::resize(src, dst, size);
+                if (fit == 3) {
+                    int left = (num_cols - size.width) / 2;
+                    int top = (num_rows - size.height) / 2;
+                    cv::Scalar colour{static_cast<double>(background & 0xFF),
+                                      static_cast<double>((background >> 8) & 0xFF),
+                                      static_cast<double>((background >> 16) & 0xFF),
+                                      255};
+                    cv::Mat padded;
+                    cv::copyMakeBorder(dst, padded, top, num_rows - size.height - top,
+                                       left, num_cols - size.width - left, cv::BORDER_CONSTANT, colour);
+                    dst = padded;
+                }
+                break;
+            }
+            case 2: {
+                // Excess is cropped from src before resizing,
+                // so the whole scaled src is never allocated
+                double scale = std::max(scale_cols, scale_rows);
+                cv::Size size{std::min(src.cols, std::max(1, static_cast<int>(num_cols / scale + 0.5))),
+                              std::min(src.rows, std::max(1, static_cast<int>(num_rows / scale + 0.5)))};
+                cv::Rect crop{(src.cols - size.width) / 2, (src.rows - size.height) / 2, size.width, size.height};
+                cv::resize(src(crop), dst, cv::Size{num_cols, num_rows});
+                break;
+            }
+            default:
+                return -2;
+        }
 
-        cv::InputOutputArray dst{dst_m};
-        cv::resize(src, dst, dst_m.size());
         std::vector <uint8_t> buff{};
-        cv::imencode(".jpg", dst, buff);
+        cv::imencode(extension, dst, buff, params);
 
-        store(out_ptr, buff.data(), buff.size());
+        store_function(output, buff.data(), buff.size());
     }
     catch(...) {
         return -4;
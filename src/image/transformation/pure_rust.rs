@@ -0,0 +1,118 @@
+//! Implementation of resizing image with image crate, without native dependencies
+use super::{Encoding, Fit, Orientation, OutputFormat, Rgb};
+use crate::image::ImageFormat;
+use image_rs::codecs::jpeg::JpegEncoder;
+use image_rs::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image_rs::imageops::{self, FilterType};
+use image_rs::{ColorType, DynamicImage, GenericImageView, ImageBuffer, Pixel, RgbImage, RgbaImage};
+
+/// Formats decoded by image crate, with enabled features of the crate
+pub const INPUT_FORMATS: &[ImageFormat] = ImageFormat::ALL;
+
+/// Formats encoded by image crate. It cannot encode webp
+pub const OUTPUT_FORMATS: &[OutputFormat] = &[OutputFormat::Jpeg, OutputFormat::Png];
+
+/// Scales `size` by `scale`, result is at least 1 pixel
+fn scaled(size: u32, scale: f64) -> u32 {
+    ((size as f64 * scale).round() as u32).max(1)
+}
+
+/// Fits `src` to cols x rows by `fit`, padding is filled with `background`
+fn fit_image<P>(src: &ImageBuffer<P, Vec<u8>>, cols: u32, rows: u32, fit: Fit, background: P) -> ImageBuffer<P, Vec<u8>>
+    where P: Pixel<Subpixel=u8> + 'static
+{
+    let scale_cols = cols as f64 / src.width() as f64;
+    let scale_rows = rows as f64 / src.height() as f64;
+    match fit {
+        Fit::Fill => imageops::resize(src, cols, rows, FilterType::Triangle),
+        Fit::Contain | Fit::Pad(_) => {
+            let scale = scale_cols.min(scale_rows);
+            let width = scaled(src.width(), scale).min(cols);
+            let height = scaled(src.height(), scale).min(rows);
+            let resized = imageops::resize(src, width, height, FilterType::Triangle);
+            match fit {
+                Fit::Pad(_) => {
+                    let mut padded = ImageBuffer::from_pixel(cols, rows, background);
+                    imageops::replace(&mut padded, &resized, (cols - width) / 2, (rows - height) / 2);
+                    padded
+                }
+                _ => resized,
+            }
+        }
+        // Excess is cropped from source before resizing,
+        // so pixels are never allocated for the whole scaled source
+        Fit::Cover => {
+            let scale = scale_cols.max(scale_rows);
+            let width = scaled(cols, 1.0 / scale).min(src.width());
+            let height = scaled(rows, 1.0 / scale).min(src.height());
+            let cropped = imageops::crop_imm(src, (src.width() - width) / 2, (src.height() - height) / 2, width, height);
+            imageops::resize(&cropped, cols, rows, FilterType::Triangle)
+        }
+    }
+}
+
+/// Rotates and flips `image` by EXIF `orientation`, so that it is upright
+fn oriented(image: DynamicImage, orientation: Orientation) -> DynamicImage {
+    match orientation.0 {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Compression type of png for level 0-9
+fn compression(level: u8) -> CompressionType {
+    match level {
+        0..=2 => CompressionType::Fast,
+        3..=6 => CompressionType::Default,
+        _ => CompressionType::Best,
+    }
+}
+
+/// Safe function of getting data of transformed image.
+/// Returns binary data of image, rotated by `orientation`,
+/// fitted to cols x rows by `fit` and encoded by `encoding`
+///
+/// # Errors
+/// If data cannot be decoded as image
+/// If format is not in ['OUTPUT_FORMATS']
+///
+pub fn transform_image(data: &[u8], cols: usize, rows: usize, fit: Fit, encoding: Encoding,
+                       orientation: Orientation) -> Option<Vec<u8>> {
+    if data.is_empty() || cols == 0 || rows == 0 {
+        return None;
+    }
+    let (cols, rows) = (cols as u32, rows as u32);
+    let src = oriented(image_rs::load_from_memory(data).ok()?, orientation);
+    if src.width() == 0 || src.height() == 0 {
+        return None;
+    }
+    let Rgb(red, green, blue) = match fit {
+        Fit::Pad(background) => background,
+        _ => Rgb(0, 0, 0),
+    };
+
+    let mut buff = vec![];
+    match encoding.format {
+        OutputFormat::Jpeg => {
+            let dst: RgbImage = fit_image(&src.to_rgb8(), cols, rows, fit, image_rs::Rgb([red, green, blue]));
+            JpegEncoder::new_with_quality(&mut buff, encoding.quality.clamp(1, 100))
+                .encode(&dst, dst.width(), dst.height(), ColorType::Rgb8)
+                .ok()?;
+        }
+        // Png keeps transparency
+        OutputFormat::Png => {
+            let dst: RgbaImage = fit_image(&src.to_rgba8(), cols, rows, fit, image_rs::Rgba([red, green, blue, 255]));
+            PngEncoder::new_with_quality(&mut buff, compression(encoding.quality), PngFilter::Sub)
+                .encode(&dst, dst.width(), dst.height(), ColorType::Rgba8)
+                .ok()?;
+        }
+        OutputFormat::Webp => return None,
+    }
+    Some(buff)
+}
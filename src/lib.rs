@@ -2,12 +2,15 @@
 extern crate log;
 
 use futures::executor::block_on;
+use futures::future::FutureExt;
+use actix_web::dev::Service;
+use actix_web::web;
 
 pub mod image;
 pub mod server;
 
 /// Run Rest-API.
-/// This function initialises database, server with routes
+/// This function initialises storage, server with routes
 /// and create SIGINT handle, which stops server gracefully
 ///
 /// # Errors
@@ -16,9 +19,40 @@ pub mod server;
 /// # Panics
 /// Will panic if database initialization fails
 ///
-pub async fn run(host: String, port: String) -> std::io::Result<()> {
-    let server = actix_web::HttpServer::new(||
+pub async fn run(host: String, port: String, config: server::Config) -> std::io::Result<()> {
+    let layout = if config.content_addressed {
+        server::Layout::ContentAddressed
+    } else {
+        server::Layout::ByName
+    };
+    let storage: server::Storage = web::Data::new(server::Gallery::new(
+        Box::new(server::FsStore::new("./images")),
+        layout,
+        config.renditions.clone(),
+    ));
+    let pool = web::Data::new(server::BlockingPool::new(
+        config.blocking_threads,
+        config.blocking_queue_depth,
+        config.retry_after,
+    ));
+    let jobs = web::Data::new(server::Jobs::new(
+        std::time::Duration::from_secs(config.job_ttl),
+        config.max_pending_jobs,
+        config.retry_after,
+    ));
+    let config = web::Data::new(config);
+
+    let server = actix_web::HttpServer::new(move ||
         actix_web::App::new()
+            .app_data(storage.clone())
+            .app_data(config.clone())
+            .app_data(pool.clone())
+            .app_data(jobs.clone())
+            .wrap_fn(|request, service| {
+                let request_id = server::RequestId::assign(&request);
+                service.call(request)
+                    .map(move |response| response.map(|response| request_id.attach(response)))
+            })
             .configure(server::init_routes)
     )
         .bind(format!("{}:{}", host, port))?
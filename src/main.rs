@@ -1,4 +1,3 @@
-use dotenv;
 use std::env;
 
 
@@ -10,5 +9,7 @@ async fn main() -> std::io::Result<()> {
     let host = env::var("HOST").expect("Host not set");
     let port = env::var("PORT").expect("Port not set");
 
-    image_api::run(host, port).await
+    let config = image_api::server::Config::from_env();
+
+    image_api::run(host, port, config).await
 }
\ No newline at end of file
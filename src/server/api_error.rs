@@ -1,12 +1,17 @@
-use actix_web::ResponseError;
+// failure's derive implements traits inside of a constant
+#![allow(non_local_definitions)]
+use actix_web::{HttpResponse, ResponseError};
 use actix_web::http::{StatusCode, header};
+use serde_json::{json, Value};
 
 // use diesel::result::Error as DieselError;
 use base64::DecodeError;
 use failure::Fail;
 
-use crate::image::ImageError;
-use actix_web::client::{SendRequestError, PayloadError};
+use crate::image::{ImageError, ImageFormat, OUTPUT_FORMATS};
+use crate::server::request_id::RequestId;
+use crate::server::response::ErrorBody;
+use actix_web::client::{ConnectError, SendRequestError, PayloadError};
 use actix_multipart::MultipartError;
 
 /// Used for processing logic errors, invalid requests and for handing errors
@@ -16,8 +21,48 @@ use actix_multipart::MultipartError;
 pub enum ApiError {
     #[fail(display = "Base64 decoding failed")]
     Base64Decoding,
-    #[fail(display = "Invalid url. Cannot be localhost")]
-    LocalhostUrl,
+    #[fail(display = "Forbidden destination: {}", _0)]
+    ForbiddenDestination(String),
+    #[fail(display = "Too many redirects, at most {} are followed", _0)]
+    TooManyRedirects(usize),
+    #[fail(display = "Remote server responded with status {}", _0)]
+    UpstreamStatus(u16),
+    #[fail(display = "Remote content is not an image: {}", _0)]
+    NotAnImage(String),
+    #[fail(display = "Remote image is larger than {} bytes", _0)]
+    DownloadTooLarge(usize),
+    #[fail(display = "Remote server did not accept connection in time")]
+    ConnectTimeout,
+    #[fail(display = "Remote server did not respond in time")]
+    DownloadTimeout,
+    #[fail(display = "Multipart field has no content disposition")]
+    MissingDisposition,
+    #[fail(display = "Multipart field has no filename")]
+    MissingFilename,
+    #[fail(display = "File is larger than {} bytes", _0)]
+    FileTooLarge(usize),
+    #[fail(display = "Request is larger than {} bytes", _0)]
+    RequestTooLarge(usize),
+    #[fail(display = "Image format {} is not accepted", _0)]
+    FormatNotAccepted(ImageFormat),
+    #[fail(display = "Invalid parameter: {}", _0)]
+    InvalidParameter(String),
+    #[fail(display = "None of output formats is acceptable: {}", _0)]
+    NotAcceptable(String),
+    #[fail(display = "Too many images in request, at most {} are allowed", _0)]
+    BatchTooLarge(usize),
+    #[fail(display = "Invalid cursor")]
+    InvalidCursor,
+    #[fail(display = "Image {} not found", _0)]
+    NotFound(String),
+    #[fail(display = "Job {} not found", _0)]
+    JobNotFound(String),
+    #[fail(display = "Image {} already exists", _0)]
+    NameConflict(String),
+    #[fail(display = "Server is overloaded, retry after {} seconds", _0)]
+    Overloaded(u64),
+    #[fail(display = "Blocking task canceled")]
+    BlockingCanceled,
     #[fail(display = "{}", _0)]
     Image(ImageError),
     #[fail(display = "{}", _0)]
@@ -50,7 +95,11 @@ impl From<PayloadError> for ApiError {
 
 impl From<SendRequestError> for ApiError {
     fn from(e: SendRequestError) -> Self {
-        ApiError::SendRequest(format!("{}", e))
+        match e {
+            SendRequestError::Connect(ConnectError::Timeout) => ApiError::ConnectTimeout,
+            SendRequestError::Timeout => ApiError::DownloadTimeout,
+            e => ApiError::SendRequest(format!("{}", e)),
+        }
     }
 }
 
@@ -68,25 +117,94 @@ impl From<DecodeError> for ApiError {
     }
 }
 
+impl ApiError {
+    /// Stable machine readable kind of error, reported to clients
+    pub fn kind(&self) -> &'static str {
+        use ApiError::*;
+        match self {
+            Base64Decoding => "invalid_base64",
+            ForbiddenDestination(_) => "forbidden_destination",
+            TooManyRedirects(_) => "too_many_redirects",
+            UpstreamStatus(_) | SendRequest(_) | Payload(_) => "download_failed",
+            ConnectTimeout => "connect_timeout",
+            DownloadTimeout => "download_timeout",
+            NotAnImage(_) => "not_an_image",
+            DownloadTooLarge(_) | FileTooLarge(_) | RequestTooLarge(_) => "too_large",
+            MissingDisposition => "missing_disposition",
+            MissingFilename => "missing_filename",
+            Multipart(_) => "invalid_multipart",
+            InvalidParameter(_) => "invalid_parameter",
+            NotAcceptable(_) => "not_acceptable",
+            BatchTooLarge(_) => "batch_too_large",
+            InvalidCursor => "invalid_cursor",
+            NotFound(_) | JobNotFound(_) => "not_found",
+            NameConflict(_) => "name_conflict",
+            Overloaded(_) => "overloaded",
+            Image(ImageError::UnsupportedImageFormat) | FormatNotAccepted(_) => "unsupported_format",
+            Image(ImageError::InvalidName(_)) => "invalid_name",
+            Image(ImageError::TooLarge(_)) => "too_large",
+            Image(ImageError::DimensionsExceeded(_)) => "dimensions_exceeded",
+            Image(ImageError::PreviewGeneration) | Image(ImageError::Transformation) => "transformation_failed",
+            BlockingCanceled | IO(_) => "internal",
+        }
+    }
+
+    /// Values, which clients may use to handle error
+    pub fn details(&self) -> Option<Value> {
+        use ApiError::*;
+        match self {
+            TooManyRedirects(limit) | DownloadTooLarge(limit) | FileTooLarge(limit)
+            | RequestTooLarge(limit) | BatchTooLarge(limit)
+            | Image(ImageError::TooLarge(limit)) => Some(json!({ "limit": limit })),
+            UpstreamStatus(status) => Some(json!({ "status": status })),
+            NotAnImage(content_type) => Some(json!({ "content_type": content_type })),
+            NotFound(name) | NameConflict(name) => Some(json!({ "name": name })),
+            JobNotFound(id) => Some(json!({ "job": id })),
+            FormatNotAccepted(format) => Some(json!({ "format": format })),
+            NotAcceptable(_) => Some(json!({ "formats": OUTPUT_FORMATS })),
+            Overloaded(retry_after) => Some(json!({ "retry_after": retry_after })),
+            Image(ImageError::InvalidName(reason))
+            | Image(ImageError::DimensionsExceeded(reason)) => Some(json!({ "reason": reason })),
+            _ => None,
+        }
+    }
+
+    /// JSON response describing error, see ['ErrorBody']
+    pub fn response(&self, request_id: Option<&RequestId>) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code());
+        if let ApiError::Overloaded(retry_after) = self {
+            resp.header(header::RETRY_AFTER, retry_after.to_string());
+        }
+        resp.json(ErrorBody::new(self, request_id))
+    }
+}
+
+/// The only mapping of errors to HTTP status codes,
+/// used for responses and for entries of images
 impl ResponseError for ApiError {
     fn status_code(&self) -> StatusCode {
         use ApiError::*;
         match *self {
-            Base64Decoding | LocalhostUrl => StatusCode::BAD_REQUEST,
+            Base64Decoding | ForbiddenDestination(_) | TooManyRedirects(_) | InvalidCursor | InvalidParameter(_) | BatchTooLarge(_)
+            | MissingDisposition | MissingFilename | Multipart(_)
+            | Image(ImageError::InvalidName(_)) => StatusCode::BAD_REQUEST,
+            NotFound(_) | JobNotFound(_) => StatusCode::NOT_FOUND,
+            NameConflict(_) => StatusCode::CONFLICT,
+            NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            DownloadTooLarge(_) | FileTooLarge(_) | RequestTooLarge(_)
+            | Image(ImageError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            Image(ImageError::DimensionsExceeded(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+            NotAnImage(_) | Image(ImageError::UnsupportedImageFormat)
+            | FormatNotAccepted(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UpstreamStatus(_) | SendRequest(_) | Payload(_) => StatusCode::BAD_GATEWAY,
+            ConnectTimeout | DownloadTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
-    fn error_response(&self) -> actix_web::HttpResponse {
-        let mut resp = actix_web::HttpResponse::build(self.status_code());
-        resp.header(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-
-        match self.status_code() {
-            StatusCode::INTERNAL_SERVER_ERROR => resp.finish(),
-            _ => resp.body(format!("{}", self))
-        }
+    /// Request id is added by ['RequestId::attach']
+    fn error_response(&self) -> HttpResponse {
+        self.response(None)
     }
 }
@@ -0,0 +1,212 @@
+//! Server preferences
+//! Red once at startup from environment variables (see .env.example)
+use crate::image::{Encoding, ImageFormat, Limits, OutputFormat, Strip, INPUT_FORMATS};
+use crate::server::{Destination, FormatPolicy};
+
+use std::env;
+use std::str::FromStr;
+
+/// Preferences of server, shared between workers
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Store image's content once under its SHA-256 digest,
+    /// names become references to digests. Env: CONTENT_ADDRESSED
+    pub content_addressed: bool,
+    /// Widths of renditions generated for every uploaded image
+    /// in addition to preview. Env: RENDITIONS, comma separated list
+    pub renditions: Vec<u32>,
+    /// Limits of size of images resized on demand. Env: MAX_RESIZE_WIDTH, MAX_RESIZE_HEIGHT
+    pub max_resize_width: u32,
+    pub max_resize_height: u32,
+    /// Threads for transformations and storage access. Env: BLOCKING_THREADS
+    pub blocking_threads: usize,
+    /// Jobs waiting for blocking threads, further jobs are rejected
+    /// with 503 Service Unavailable. Env: BLOCKING_QUEUE_DEPTH
+    pub blocking_queue_depth: usize,
+    /// Seconds in Retry-After header of rejected requests. Env: RETRY_AFTER
+    pub retry_after: u64,
+    /// Seconds during which status of finished asynchronous upload is kept. Env: JOB_TTL
+    pub job_ttl: u64,
+    /// Asynchronous uploads pending or running at once, further ones are rejected
+    /// with 503 Service Unavailable. Env: MAX_PENDING_JOBS
+    pub max_pending_jobs: usize,
+    /// Images of one request downloaded or decoded at once. Env: EXTRACT_CONCURRENCY
+    pub extract_concurrency: usize,
+    /// Images in one JSON request, larger requests are rejected
+    /// with 400 Bad Request. Env: MAX_BATCH_SIZE
+    pub max_batch_size: usize,
+    /// Destinations of image URLs trusted even if they are internal.
+    /// Host names (".example.com" for subdomains) or networks like "10.1.0.0/16".
+    /// Env: URL_ALLOW, comma separated list
+    pub url_allow: Vec<Destination>,
+    /// Destinations of image URLs always rejected, in the same form. Env: URL_DENY
+    pub url_deny: Vec<Destination>,
+    /// Seconds to connect to server of image URL. Env: DOWNLOAD_CONNECT_TIMEOUT
+    pub download_connect_timeout: u64,
+    /// Seconds to wait for response and for every chunk of its body. Env: DOWNLOAD_READ_TIMEOUT
+    pub download_read_timeout: u64,
+    /// Redirects followed while downloading image. Env: MAX_REDIRECTS
+    pub max_redirects: usize,
+    /// Bytes of downloaded image, larger images are rejected
+    /// with 413 Payload Too Large. Env: MAX_DOWNLOAD_SIZE
+    pub max_download_size: usize,
+    /// Bytes of one file of multipart request. Env: MAX_FILE_SIZE
+    pub max_file_size: usize,
+    /// Bytes of all fields of multipart request. Env: MAX_REQUEST_SIZE
+    pub max_request_size: usize,
+    /// Formats of accepted images, other uploads are rejected
+    /// with 415 Unsupported Media Type. Defaults to formats decoded by enabled backend,
+    /// see ['INPUT_FORMATS']. Env: FORMATS, comma separated list
+    pub formats: Vec<ImageFormat>,
+    /// Format of previews and renditions: "auto" follows format of original
+    /// (see ['FormatPolicy::Auto']), or one of jpeg, png, webp. Env: DERIVED_FORMAT
+    pub derived_format: FormatPolicy,
+    /// Quality of jpeg from 1 to 100. Env: JPEG_QUALITY
+    pub jpeg_quality: u8,
+    /// Compression level of png from 0 to 9. Env: PNG_COMPRESSION
+    pub png_compression: u8,
+    /// Quality of webp from 1 to 100, 100 is lossless. Env: WEBP_QUALITY
+    pub webp_quality: u8,
+    /// EXIF metadata removed from originals of uploaded images: none, gps or all.
+    /// Orientation is always kept. Env: STRIP_EXIF
+    pub strip_exif: Strip,
+    /// Limits of uploaded images, checked against their headers before decoding.
+    /// Larger files are rejected with 413 Payload Too Large, larger dimensions
+    /// with 422 Unprocessable Entity. Env: MAX_IMAGE_WIDTH, MAX_IMAGE_HEIGHT, MAX_IMAGE_PIXELS, MAX_IMAGE_BYTES
+    pub max_image_width: u32,
+    pub max_image_height: u32,
+    pub max_image_pixels: u64,
+    pub max_image_bytes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            content_addressed: false,
+            renditions: vec![],
+            max_resize_width: 2048,
+            max_resize_height: 2048,
+            blocking_threads: 4,
+            blocking_queue_depth: 64,
+            retry_after: 1,
+            job_ttl: 3600,
+            max_pending_jobs: 16,
+            extract_concurrency: 8,
+            max_batch_size: 50,
+            url_allow: vec![],
+            url_deny: vec![],
+            download_connect_timeout: 5,
+            download_read_timeout: 10,
+            max_redirects: 5,
+            max_download_size: 20 * 1024 * 1024,
+            max_file_size: 20 * 1024 * 1024,
+            max_request_size: 100 * 1024 * 1024,
+            formats: INPUT_FORMATS.to_vec(),
+            derived_format: FormatPolicy::Auto,
+            jpeg_quality: Encoding::new(OutputFormat::Jpeg).quality,
+            png_compression: Encoding::new(OutputFormat::Png).quality,
+            webp_quality: Encoding::new(OutputFormat::Webp).quality,
+            strip_exif: Strip::Nothing,
+            max_image_width: Limits::default().max_width,
+            max_image_height: Limits::default().max_height,
+            max_image_pixels: Limits::default().max_pixels,
+            max_image_bytes: Limits::default().max_bytes,
+        }
+    }
+}
+
+impl Config {
+    /// Reads preferences from environment.
+    /// Unset variables take default values
+    ///
+    /// # Panics
+    /// If variable is set, but cannot be parsed
+    /// If quality is out of range
+    /// If derived format cannot be encoded by enabled backend
+    ///
+    pub fn from_env() -> Self {
+        let default = Config::default();
+        let config = Config {
+            content_addressed: var("CONTENT_ADDRESSED", default.content_addressed),
+            renditions: list("RENDITIONS", default.renditions),
+            max_resize_width: var("MAX_RESIZE_WIDTH", default.max_resize_width),
+            max_resize_height: var("MAX_RESIZE_HEIGHT", default.max_resize_height),
+            blocking_threads: var("BLOCKING_THREADS", default.blocking_threads),
+            blocking_queue_depth: var("BLOCKING_QUEUE_DEPTH", default.blocking_queue_depth),
+            retry_after: var("RETRY_AFTER", default.retry_after),
+            job_ttl: var("JOB_TTL", default.job_ttl),
+            max_pending_jobs: var("MAX_PENDING_JOBS", default.max_pending_jobs),
+            extract_concurrency: var("EXTRACT_CONCURRENCY", default.extract_concurrency),
+            max_batch_size: var("MAX_BATCH_SIZE", default.max_batch_size),
+            url_allow: list("URL_ALLOW", default.url_allow),
+            url_deny: list("URL_DENY", default.url_deny),
+            download_connect_timeout: var("DOWNLOAD_CONNECT_TIMEOUT", default.download_connect_timeout),
+            download_read_timeout: var("DOWNLOAD_READ_TIMEOUT", default.download_read_timeout),
+            max_redirects: var("MAX_REDIRECTS", default.max_redirects),
+            max_download_size: var("MAX_DOWNLOAD_SIZE", default.max_download_size),
+            max_file_size: var("MAX_FILE_SIZE", default.max_file_size),
+            max_request_size: var("MAX_REQUEST_SIZE", default.max_request_size),
+            formats: list("FORMATS", default.formats),
+            derived_format: var("DERIVED_FORMAT", default.derived_format),
+            jpeg_quality: var("JPEG_QUALITY", default.jpeg_quality),
+            png_compression: var("PNG_COMPRESSION", default.png_compression),
+            webp_quality: var("WEBP_QUALITY", default.webp_quality),
+            strip_exif: var("STRIP_EXIF", default.strip_exif),
+            max_image_width: var("MAX_IMAGE_WIDTH", default.max_image_width),
+            max_image_height: var("MAX_IMAGE_HEIGHT", default.max_image_height),
+            max_image_pixels: var("MAX_IMAGE_PIXELS", default.max_image_pixels),
+            max_image_bytes: var("MAX_IMAGE_BYTES", default.max_image_bytes),
+        };
+        assert!((1..=100).contains(&config.jpeg_quality), "JPEG_QUALITY must be from 1 to 100");
+        assert!(config.png_compression <= 9, "PNG_COMPRESSION must be from 0 to 9");
+        assert!((1..=100).contains(&config.webp_quality), "WEBP_QUALITY must be from 1 to 100");
+        if let FormatPolicy::Fixed(format) = config.derived_format {
+            assert!(format.is_supported(), "DERIVED_FORMAT {} is not supported by enabled backend", format);
+        }
+        for format in config.formats.iter().filter(|format| !format.is_supported()) {
+            // Newer versions of backend's library may decode it
+            warn!("FORMATS includes {}, which may not be decoded by enabled backend", format);
+        }
+        config
+    }
+
+    /// Encoding to `format` with configured quality
+    pub fn encoding(&self, format: OutputFormat) -> Encoding {
+        let quality = match format {
+            OutputFormat::Jpeg => self.jpeg_quality,
+            OutputFormat::Png => self.png_compression,
+            OutputFormat::Webp => self.webp_quality,
+        };
+        Encoding { format, quality }
+    }
+
+    /// Limits of uploaded images
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_width: self.max_image_width,
+            max_height: self.max_image_height,
+            max_pixels: self.max_image_pixels,
+            max_bytes: self.max_image_bytes,
+        }
+    }
+}
+
+fn var<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse()
+            .unwrap_or_else(|_| panic!("{} is not valid: {}", key, value)),
+        Err(_) => default,
+    }
+}
+
+fn list<T: FromStr>(key: &str, default: Vec<T>) -> Vec<T> {
+    match env::var(key) {
+        Ok(value) => value.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse()
+                .unwrap_or_else(|_| panic!("{} is not valid: {}", key, value)))
+            .collect(),
+        Err(_) => default,
+    }
+}
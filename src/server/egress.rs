@@ -0,0 +1,366 @@
+//! Policy of outgoing requests for images by URL.
+//! Protects internal services from requests forged by clients (SSRF):
+//! destinations are checked by resolved addresses, not by text of URL,
+//! and connections are made to the checked addresses only
+use crate::server::{ApiError, Config};
+
+use actix_web::http::Uri;
+use actix_web::web;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+/// Range of addresses in CIDR notation, like "10.0.0.0/8".
+/// Single address is a range with full prefix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) =>
+                masked(u32::from(network) as u128, u32::from(*ip) as u128, self.prefix, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) =>
+                masked(u128::from(network), u128::from(*ip), self.prefix, 128),
+            _ => false,
+        }
+    }
+}
+
+/// Compares leading `prefix` bits of `bits`-wide addresses
+fn masked(network: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = (bits - prefix) as u32;
+    network >> shift == ip >> shift
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse()
+            .map_err(|_| format!("{} is not an IP address", address))?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("{} is not a valid prefix", prefix))?,
+            None => bits,
+        };
+        Ok(Network { address, prefix })
+    }
+}
+
+/// Entry of allow and deny lists: network of addresses
+/// or host name. Host name with leading dot, like ".example.com",
+/// matches all its subdomains
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+    Network(Network),
+    Host(String),
+}
+
+impl Destination {
+    fn covers(&self, host: &str, address: &IpAddr) -> bool {
+        match self {
+            Destination::Network(network) => network.contains(address),
+            Destination::Host(name) if name.starts_with('.') =>
+                host.ends_with(name.as_str()) || host == &name[1..],
+            Destination::Host(name) => host == name,
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("destination is empty".to_string());
+        }
+        if s.contains('/') || s.parse::<IpAddr>().is_ok() {
+            return s.parse().map(Destination::Network);
+        }
+        Ok(Destination::Host(s.to_lowercase()))
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::Network(network) => write!(f, "{}/{}", network.address, network.prefix),
+            Destination::Host(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Kind of address, which must not be reachable by clients
+fn internal(ip: &IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => internal_v4(ip),
+        IpAddr::V6(ip) => internal_v6(ip),
+    }
+}
+
+fn internal_v4(ip: &Ipv4Addr) -> Option<&'static str> {
+    let octets = ip.octets();
+    if octets == [169, 254, 169, 254] || octets == [100, 100, 100, 200] {
+        Some("cloud metadata")
+    } else if octets[0] == 0 {
+        Some("unspecified")
+    } else if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_private() {
+        Some("private")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if octets[0] == 100 && octets[1] & 0xC0 == 64 {
+        Some("shared (carrier-grade NAT)")
+    } else if octets[0] == 198 && octets[1] & 0xFE == 18 {
+        Some("benchmarking")
+    } else if [[192, 0, 2], [198, 51, 100], [203, 0, 113]].contains(&[octets[0], octets[1], octets[2]]) {
+        Some("documentation")
+    } else if ip.is_multicast() || ip.is_broadcast() {
+        Some("multicast")
+    } else if octets[0] >= 240 || octets[..3] == [192, 0, 0] {
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+/// IPv4 address reached through IPv6 address: IPv4-mapped ::ffff:0:0/96,
+/// IPv4-compatible ::/96, NAT64 64:ff9b::/96 and 6to4 2002::/16
+fn embedded_v4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xFFFF, ..] | [0, 0, 0, 0, 0, 0, ..] | [0x64, 0xFF9B, 0, 0, 0, 0, ..] =>
+            Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+fn internal_v6(ip: &Ipv6Addr) -> Option<&'static str> {
+    let segments = ip.segments();
+    if ip.is_unspecified() {
+        return Some("unspecified");
+    } else if ip.is_loopback() {
+        return Some("loopback");
+    }
+    if let Some(embedded) = embedded_v4(ip) {
+        return internal_v4(&embedded);
+    }
+    if segments == [0xFD00, 0x0EC2, 0, 0, 0, 0, 0, 0x0254] {
+        Some("cloud metadata")
+    } else if segments[0] & 0xFE00 == 0xFC00 {
+        Some("private")
+    } else if segments[0] & 0xFFC0 == 0xFE80 {
+        Some("link-local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if segments[0] & 0xFFC0 == 0xFEC0 {
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+fn forbidden(reason: String) -> ApiError {
+    ApiError::ForbiddenDestination(reason)
+}
+
+/// Destination allowed by policy
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checked {
+    pub uri: Uri,
+    /// Checked addresses of host. Connection must be made to one of them,
+    /// since resolving host again may give other addresses (DNS rebinding)
+    pub addresses: Vec<SocketAddr>,
+}
+
+/// Checks that `url` may be requested by server.
+/// Only http and https are allowed. Host is resolved, and every its address
+/// must be public, unless the destination is in `config.url_allow`.
+/// Destinations in `config.url_deny` are always rejected.
+///
+/// Check must be repeated for every redirect
+///
+/// # Errors
+/// If url is not valid
+/// If host cannot be resolved
+/// If destination is forbidden
+///
+pub async fn check(url: &str, config: &Config) -> Result<Checked, ApiError> {
+    let uri: Uri = url.parse()
+        .map_err(|_| ApiError::InvalidParameter(format!("url {} is not valid", url)))?;
+    let default_port = match uri.scheme_str() {
+        Some("http") => 80,
+        Some("https") => 443,
+        Some(scheme) => return Err(forbidden(format!("scheme {} is not allowed", scheme))),
+        None => return Err(ApiError::InvalidParameter(format!("url {} has no scheme", url))),
+    };
+    let host = uri.host()
+        .ok_or_else(|| ApiError::InvalidParameter(format!("url {} has no host", url)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_lowercase();
+    let port = uri.port_u16().unwrap_or(default_port);
+
+    let addresses = resolve(host.clone(), port).await?;
+
+    let denied = config.url_deny.iter()
+        .find(|destination| addresses.iter().any(|address| destination.covers(&host, address)));
+    if let Some(destination) = denied {
+        return Err(forbidden(format!("{} is denied by {}", host, destination)));
+    }
+    let allowed = config.url_allow.iter()
+        .any(|destination| addresses.iter().all(|address| destination.covers(&host, address)));
+    if !allowed {
+        for address in &addresses {
+            if let Some(kind) = internal(address) {
+                return Err(forbidden(format!("{} resolves to {} address {}", host, kind, address)));
+            }
+        }
+    }
+    let addresses = addresses.into_iter()
+        .map(|address| SocketAddr::new(address, port))
+        .collect();
+    Ok(Checked { uri, addresses })
+}
+
+/// Resolves `host` on blocking thread
+async fn resolve(host: String, port: u16) -> Result<Vec<IpAddr>, ApiError> {
+    let name = host.clone();
+    let addresses = web::block(move || (name.as_str(), port).to_socket_addrs()
+        .map(|addresses| addresses.map(|address| address.ip()).collect::<Vec<_>>()))
+        .await
+        .map_err(|_| ApiError::InvalidParameter(format!("host {} cannot be resolved", host)))?;
+    if addresses.is_empty() {
+        return Err(ApiError::InvalidParameter(format!("host {} cannot be resolved", host)));
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn destinations(list: &[&str]) -> Vec<Destination> {
+        list.iter().map(|destination| destination.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn network_contains_addresses_with_its_prefix() {
+        let network: Network = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains(&ip("10.255.0.1")));
+        assert!(!network.contains(&ip("11.0.0.1")));
+        assert!(!network.contains(&ip("::ffff:10.0.0.1")));
+
+        let network: Network = "fd00::/8".parse().unwrap();
+        assert!(network.contains(&ip("fd12::1")));
+        assert!(!network.contains(&ip("fe80::1")));
+
+        let single: Network = "192.168.1.1".parse().unwrap();
+        assert!(single.contains(&ip("192.168.1.1")));
+        assert!(!single.contains(&ip("192.168.1.2")));
+
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("::/129".parse::<Network>().is_err());
+        assert!("example.com/8".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn destination_covers_hosts_and_subdomains() {
+        let address = ip("93.184.216.34");
+        let host: Destination = "example.com".parse().unwrap();
+        assert!(host.covers("example.com", &address));
+        assert!(!host.covers("cdn.example.com", &address));
+
+        let domain: Destination = ".Example.com".parse().unwrap();
+        assert!(domain.covers("example.com", &address));
+        assert!(domain.covers("cdn.example.com", &address));
+        assert!(!domain.covers("badexample.com", &address));
+
+        let network: Destination = "93.184.216.0/24".parse().unwrap();
+        assert!(network.covers("any.host", &address));
+        assert!(!network.covers("any.host", &ip("93.184.217.1")));
+    }
+
+    #[test]
+    fn internal_v4_addresses_are_detected() {
+        for internal in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.0.1", "169.254.169.254",
+                          "169.254.1.1", "100.64.0.1", "0.0.0.0", "224.0.0.1", "255.255.255.255", "240.0.0.1",
+                          "198.18.0.1", "198.19.255.255", "192.0.2.1", "198.51.100.1", "203.0.113.1"] {
+            assert!(internal_v4(&internal.parse().unwrap()).is_some(), "{}", internal);
+        }
+        for public in &["8.8.8.8", "93.184.216.34", "100.128.0.1", "172.32.0.1", "198.17.0.1", "198.20.0.1",
+                        "192.0.3.1", "198.51.101.1", "203.0.114.1"] {
+            assert_eq!(None, internal_v4(&public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    fn internal_v6_addresses_are_detected() {
+        for internal in &["::", "::1", "fd00:ec2::254", "fc00::1", "fe80::1", "ff02::1", "fec0::1",
+                          "::ffff:127.0.0.1", "::ffff:169.254.169.254", "64:ff9b::10.0.0.1",
+                          "::127.0.0.1", "::10.0.0.1", "2002:7f00:1::", "2002:a9fe:a9fe::1"] {
+            assert!(internal_v6(&internal.parse().unwrap()).is_some(), "{}", internal);
+        }
+        for public in &["2606:2800:220:1:248:1893:25c8:1946", "::ffff:8.8.8.8", "::8.8.8.8", "2002:808:808::1"] {
+            assert_eq!(None, internal_v6(&public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn checked_addresses_are_used_for_connection() {
+        let checked = check("http://93.184.216.34:8080/cat.png", &Config::default()).await.unwrap();
+
+        assert_eq!("/cat.png", checked.uri.path());
+        assert_eq!(vec!["93.184.216.34:8080".parse::<SocketAddr>().unwrap()], checked.addresses);
+    }
+
+    #[actix_rt::test]
+    async fn internal_destinations_are_forbidden() {
+        let config = Config::default();
+        for url in &["http://127.0.0.1/", "http://[::ffff:127.0.0.1]/", "http://[::a9fe:a9fe]/",
+                     "https://[2002:a00:1::]/", "ftp://93.184.216.34/"] {
+            let checked = check(url, &config).await;
+            assert!(matches!(checked, Err(ApiError::ForbiddenDestination(_))), "{}", url);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn allow_and_deny_lists_override_policy() {
+        let config = Config {
+            url_allow: destinations(&["10.0.0.0/8"]),
+            url_deny: destinations(&["93.184.216.0/24"]),
+            ..Config::default()
+        };
+
+        assert!(check("http://10.0.0.1/", &config).await.is_ok());
+        assert!(matches!(check("http://93.184.216.34/", &config).await, Err(ApiError::ForbiddenDestination(_))));
+    }
+}
@@ -1,12 +1,23 @@
-use futures::{StreamExt, TryStreamExt};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 
 use actix_multipart::{Multipart, Field};
 use actix_web::{web, client};
+use actix_web::http::{header, Uri};
+use actix_web::client::{PayloadError, SendRequestError};
+use actix_web::web::Bytes;
+use actix_rt::time;
 use serde::{Deserialize, Serialize};
 use async_trait::async_trait;
 
-use crate::server::{ApiError};
-use crate::image::Image;
+use crate::server::{ApiError, Config};
+use crate::server::egress;
+use crate::image::{Image, Source};
+
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
 pub type ApiJsonRequest = web::Json<Vec<JsonMessage>>;
 
 pub type ApiUrlRequest = web::Json<Vec<UrlMessage>>;
@@ -25,64 +36,236 @@ pub struct UrlMessage {
     pub url: String,
 }
 
+/// Field of multipart request with a file
 pub struct MultipartField {
-    pub field: Field
+    pub field: Field,
+    /// Bytes of request red by all its fields, shared between them
+    pub received: Rc<Cell<usize>>,
 }
 
 #[async_trait(? Send)]
 pub trait TryIntoImage: Sized {
     /// Performs the conversion.
-    async fn try_into_image(self) -> Result<Image, ApiError>;
+    async fn try_into_image(self, config: &Config) -> Result<Image, ApiError>;
 }
 
 #[async_trait(? Send)]
 impl TryIntoImage for JsonMessage {
-    async fn try_into_image(self) -> Result<Image, ApiError> {
+    async fn try_into_image(self, _config: &Config) -> Result<Image, ApiError> {
         let JsonMessage { name, data } = self;
         let encoded: String = data.chars().filter(|ch| !ch.is_whitespace()).collect();
         let decoded = base64::decode(encoded.as_bytes())?;
-        let image = Image::create(name, decoded)?;
+        let image = Image::create(name, decoded, Source::Json)?;
         Ok(image)
     }
 }
 
+/// Checks whether `reference` starts with scheme, like "https:"
+fn has_scheme(reference: &str) -> bool {
+    match reference.find(|c: char| !(c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')) {
+        Some(end) => end > 0 && reference[end..].starts_with(':')
+            && reference.starts_with(|c: char| c.is_ascii_alphabetic()),
+        None => false,
+    }
+}
+
+/// Removes "." and ".." segments of absolute `path`
+fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = vec![];
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+    for (index, segment) in segments.iter().enumerate() {
+        match *segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            segment => output.push(segment),
+        }
+        // Path keeps its trailing slash
+        if index + 1 == segments.len() && (*segment == "." || *segment == "..") {
+            output.push("");
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+/// Resolves `location` of redirect against `base`, as reference is resolved by RFC 3986.
+/// Fragment is dropped, since it is not sent to servers
+fn redirect_target(base: &Uri, location: &str) -> String {
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority().map(|authority| authority.as_str()).unwrap_or("");
+    let location = location.split('#').next().unwrap_or("");
+    if has_scheme(location) {
+        return location.to_string();
+    }
+    if location.starts_with("//") {
+        return format!("{}:{}", scheme, location);
+    }
+    let (path, query) = match location.find('?') {
+        Some(index) => (&location[..index], &location[index..]),
+        None => (location, ""),
+    };
+    let (path, query) = if path.is_empty() {
+        // Reference without path keeps path of base, and its query if there is no new one
+        let query = match (query, base.query()) {
+            ("", Some(base_query)) => format!("?{}", base_query),
+            (query, _) => query.to_string(),
+        };
+        (base.path().to_string(), query)
+    } else if path.starts_with('/') {
+        (remove_dot_segments(path), query.to_string())
+    } else {
+        let base_path = base.path();
+        let directory = &base_path[..base_path.rfind('/').map_or(0, |index| index + 1)];
+        (remove_dot_segments(&format!("/{}{}", directory.trim_start_matches('/'), path)), query.to_string())
+    };
+    format!("{}://{}{}{}", scheme, authority, path, query)
+}
+
+/// Client with timeouts of `config`
+fn download_client(config: &Config) -> client::Client {
+    let connector = client::Connector::new()
+        .timeout(Duration::from_secs(config.download_connect_timeout))
+        .finish();
+    client::Client::build()
+        .connector(connector)
+        .timeout(Duration::from_secs(config.download_read_timeout))
+        .finish()
+}
+
+/// Sends GET request for `uri`, connecting to `address` instead of resolving its host
+async fn send(client: &client::Client, uri: &Uri, address: SocketAddr)
+    -> Result<client::ClientResponse<impl Stream<Item=Result<Bytes, PayloadError>> + Unpin>, SendRequestError>
+{
+    client.get(uri.clone())
+        .address(address)
+        .header("User-Agent", "test_rest_api")
+        .send()
+        .await
+}
+
+/// Reads body of response, at most `config.max_download_size` bytes.
+/// Every chunk must arrive in `config.download_read_timeout`
+async fn read_body<S>(response: &mut client::ClientResponse<S>, config: &Config) -> Result<Vec<u8>, ApiError>
+    where S: Stream<Item=Result<Bytes, PayloadError>> + Unpin
+{
+    let limit = config.max_download_size;
+    let length = response.headers().get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return Err(ApiError::DownloadTooLarge(limit));
+    }
+
+    let mut buff = Vec::with_capacity(length.unwrap_or(0));
+    let read_timeout = Duration::from_secs(config.download_read_timeout);
+    while let Some(chunk) = time::timeout(read_timeout, response.next()).await
+        .map_err(|_| ApiError::DownloadTimeout)?
+    {
+        let chunk = chunk?;
+        if buff.len() + chunk.len() > limit {
+            return Err(ApiError::DownloadTooLarge(limit));
+        }
+        buff.extend_from_slice(&chunk);
+    }
+    Ok(buff)
+}
+
 #[async_trait(? Send)]
 impl TryIntoImage for UrlMessage {
-    async fn try_into_image(self) -> Result<Image, ApiError> {
-        let UrlMessage { name, url } = self;
-        if url.contains("localhost") || url.contains("127.0.0.1") {
-            return Err(ApiError::LocalhostUrl);
-        }
-        let client = client::Client::default();
+    /// Downloads image, following redirects manually,
+    /// so that every destination is checked by egress policy
+    async fn try_into_image(self, config: &Config) -> Result<Image, ApiError> {
+        let UrlMessage { name, url: requested } = self;
+        let client = download_client(config);
+        let mut url = requested.clone();
 
-        // Create request builder and send request
-        let mut response = client.get(url)
-            .header("User-Agent", "test_rest_api")
-            .send()
-            .await?;
+        for _ in 0..=config.max_redirects {
+            let egress::Checked { uri, addresses } = egress::check(&url, config).await?;
 
-        let data = response.body().await?.to_vec();
-        let image = Image::create(name, data)
-            .map_err(|e| ApiError::from(e))?;
-        Ok(image)
+            // Host is not resolved again: checked addresses are tried in turn
+            let mut sent = None;
+            for address in addresses {
+                let result = send(&client, &uri, address).await;
+                let unreachable = matches!(result, Err(SendRequestError::Connect(_)));
+                sent = Some(result);
+                if !unreachable {
+                    break;
+                }
+            }
+            let mut response = match sent {
+                Some(result) => result?,
+                None => return Err(ApiError::InvalidParameter(format!("host of {} cannot be resolved", uri))),
+            };
+
+            let status = response.status();
+            if status.is_redirection() {
+                if let Some(location) = response.headers().get(header::LOCATION) {
+                    let location = location.to_str()
+                        .map_err(|_| ApiError::InvalidParameter(format!("redirect from {} is not valid", uri)))?;
+                    url = redirect_target(&uri, location);
+                    continue;
+                }
+            }
+            if !status.is_success() {
+                return Err(ApiError::UpstreamStatus(status.as_u16()));
+            }
+            // Missing type is allowed, content is checked by Image::create
+            if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
+                let content_type = content_type.to_str().unwrap_or("").to_string();
+                if !content_type.trim_start().to_lowercase().starts_with("image/") {
+                    return Err(ApiError::NotAnImage(content_type));
+                }
+            }
+
+            let data = read_body(&mut response, config).await?;
+            let image = Image::create(name, data, Source::Url { url: requested })
+                .map_err(ApiError::from)?;
+            return Ok(image);
+        }
+        Err(ApiError::TooManyRedirects(config.max_redirects))
     }
 }
 
 #[async_trait(? Send)]
 impl TryIntoImage for MultipartField {
-    async fn try_into_image(self) -> Result<Image,ApiError> {
-        let mut field = self.field;
-        let content_type = field.content_disposition().unwrap();
-        let name = content_type.get_filename().unwrap().to_string();
+    /// Reads file of the field, at most `config.max_file_size` bytes.
+    /// Rejected field is still red to the end, so that its size counts
+    /// to `config.max_request_size`
+    async fn try_into_image(self, config: &Config) -> Result<Image,ApiError> {
+        let MultipartField { mut field, received } = self;
+        let name = match field.content_disposition() {
+            Some(disposition) => disposition.get_filename()
+                .map(str::to_string)
+                .ok_or(ApiError::MissingFilename),
+            None => Err(ApiError::MissingDisposition),
+        };
 
         let mut buff = vec![];
+        let mut too_large = false;
         // Field in turn is stream of *Bytes* object
         while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
+            let data = chunk?;
+            received.set(received.get() + data.len());
+            if received.get() > config.max_request_size {
+                return Err(ApiError::RequestTooLarge(config.max_request_size));
+            }
+            if name.is_err() || too_large {
+                continue;
+            }
+            if buff.len() + data.len() > config.max_file_size {
+                too_large = true;
+                buff = vec![];
+                continue;
+            }
             buff.extend_from_slice(&data);
         }
-        let image = Image::create(name, buff)
-            .map_err(|e|ApiError::from(e))?;
+        let name = name?;
+        if too_large {
+            return Err(ApiError::FileTooLarge(config.max_file_size));
+        }
+        let image = Image::create(name, buff, Source::Multipart)
+            .map_err(ApiError::from)?;
         Ok(image)
     }
 }
@@ -93,32 +276,86 @@ impl TryIntoImage for MultipartField {
 
 
 
+/// Checks that format, size and dimensions of extracted image are accepted by `config`
+/// and strips its EXIF metadata as configured
+fn accepted(image: Result<Image, ApiError>, config: &Config) -> Result<Image, ApiError> {
+    let mut image = image?;
+    if !config.formats.contains(&image.format()) {
+        return Err(ApiError::FormatNotAccepted(image.format()));
+    }
+    image.check_limits(&config.limits())?;
+    image.strip_exif(config.strip_exif);
+    Ok(image)
+}
+
 #[async_trait(? Send)]
 pub trait SupportedRequest {
-    async fn extract(self) -> Vec<Result<Image, ApiError>>;
+    /// Whether images are red from request's payload during extraction.
+    /// Such requests must be extracted before response is sent
+    const STREAMED: bool = false;
+
+    /// Checks limits of the whole request before extraction
+    ///
+    /// # Errors
+    /// If request exceeds limits of `config`
+    ///
+    fn validate(&self, _config: &Config) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    async fn extract(self, config: &Config) -> Vec<Result<Image, ApiError>>;
 }
 
 #[async_trait(? Send)]
 impl<T> SupportedRequest for web::Json<Vec<T>>
     where T: TryIntoImage
 {
-    async fn extract(self) -> Vec<Result<Image, ApiError>> {
-        let messages = self.into_inner();
-        let mut images = vec![];
-        for message in messages {
-            images.push(message.try_into_image().await);
+    fn validate(&self, config: &Config) -> Result<(), ApiError> {
+        if self.len() > config.max_batch_size {
+            return Err(ApiError::BatchTooLarge(config.max_batch_size));
         }
-        images
+        Ok(())
+    }
+
+    /// Messages are converted concurrently, at most `config.extract_concurrency` at once.
+    /// Images are in order of messages
+    async fn extract(self, config: &Config) -> Vec<Result<Image, ApiError>> {
+        let messages = self.into_inner();
+        stream::iter(messages)
+            .map(|message| message.try_into_image(config))
+            .buffered(config.extract_concurrency.max(1))
+            .map(|image| accepted(image, config))
+            .collect()
+            .await
     }
 }
 
 #[async_trait(? Send)]
 impl SupportedRequest for Multipart {
-    async fn extract(mut self) -> Vec<Result<Image,ApiError>> {
+    const STREAMED: bool = true;
+
+    /// Every field results in image or error.
+    /// Extraction stops when request is broken or too large
+    async fn extract(mut self, config: &Config) -> Vec<Result<Image,ApiError>> {
         let mut images = vec![];
-        while let Ok(Some(field)) = self.try_next().await {
-            let field = MultipartField { field };
-            images.push(field.try_into_image().await);
+        let received = Rc::new(Cell::new(0));
+        loop {
+            match self.try_next().await {
+                Ok(Some(field)) => {
+                    let field = MultipartField { field, received: received.clone() };
+                    let image = accepted(field.try_into_image(config).await, config);
+                    let stop = matches!(&image, Err(ApiError::RequestTooLarge(_)) | Err(ApiError::Multipart(_)));
+                    images.push(image);
+                    if stop {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    images.push(Err(e.into()));
+                    break;
+                }
+            }
         }
         images
     }
@@ -160,52 +397,93 @@ impl SupportedRequest for Multipart {
 
 */
 //
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use actix_web::{web, ResponseError};
-//     use actix_web::http::{HeaderMap, HeaderName, header, HeaderValue};
-//
-//     const REFERENCE_PNG: &[u8] = &[137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0, 31, 21, 196, 137, 0, 0, 0, 4, 115, 66, 73, 84, 8, 8, 8, 8, 124, 8, 100, 136, 0, 0, 0, 11, 73, 68, 65, 84, 8, 153, 99, 248, 15, 4, 0, 9, 251, 3, 253, 227, 85, 242, 156, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130];
-//
-//
-//     #[actix_rt::test]
-//     async fn from_json() {
-//         let simple_json = web::Json(JsonMessage {
-//             data: base64::encode(REFERENCE_PNG)
-//         });
-//
-//         let req = SupportedRequest::from(simple_json);
-//
-//         let res = req.try_extract().await.unwrap();
-//
-//         assert_eq!(REFERENCE_PNG, res.as_slice());
-//     }
-//
-//
-//     #[actix_rt::test]
-//     async fn from_json_invalid() {
-//         let simple_json = web::Json(JsonMessage {
-//             data: "invalid base64".to_string()
-//         });
-//
-//         let req = SupportedRequest::from(simple_json);
-//
-//         let res = req.try_extract().await;
-//         assert!(res.is_err());
-//         assert_eq!(ApiError::Base64Decoding.status_code(), res.unwrap_err().as_response_error().status_code());
-//     }
-//
-//     // #[actix_rt::test]
-//     // async fn from_url() {
-//     //     let simple_json = web::Form(FormUrl {
-//     //         url:
-//     //     });
-//     //
-//     //     let req = SupportedRequest::from(simple_json);
-//     //
-//     //     let res = req.try_extract().await.unwrap();
-//     //
-//     //     assert_eq!(REFERENCE_PNG, res.as_slice());
-//     // }
-// }
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App, HttpResponse};
+
+    const LANDSCAPE_PNG: &[u8] = include_bytes!("../../tests/data/landscape.png");
+
+    fn resolved(base: &str, location: &str) -> String {
+        redirect_target(&base.parse().unwrap(), location)
+    }
+
+    #[test]
+    fn redirect_target_is_resolved_against_base() {
+        let base = "http://a.example/b/c/d?q";
+        assert_eq!("https://other.example/x", resolved(base, "https://other.example/x"));
+        assert_eq!("http://other.example/x", resolved(base, "//other.example/x"));
+        assert_eq!("http://a.example/g", resolved(base, "/g"));
+        assert_eq!("http://a.example/b/c/g", resolved(base, "g"));
+        assert_eq!("http://a.example/b/c/g/", resolved(base, "./g/"));
+        assert_eq!("http://a.example/b/g", resolved(base, "../g"));
+        assert_eq!("http://a.example/g", resolved(base, "../../../g"));
+        assert_eq!("http://a.example/b/c/", resolved(base, "."));
+        assert_eq!("http://a.example/b/", resolved(base, ".."));
+    }
+
+    #[test]
+    fn redirect_target_without_path_keeps_base_path() {
+        let base = "http://a.example/b/c/d?q";
+        assert_eq!("http://a.example/b/c/d?x=1", resolved(base, "?x=1"));
+        assert_eq!("http://a.example/b/c/d?q", resolved(base, ""));
+        assert_eq!("http://a.example/b/c/d?q", resolved(base, "#s"));
+        assert_eq!("http://a.example/b/c/g?y", resolved(base, "g?y#s"));
+    }
+
+    #[test]
+    fn redirect_target_with_scheme_in_query_is_relative() {
+        let base = "http://a.example/b/c";
+        assert_eq!("http://a.example/r?to=http://x", resolved(base, "/r?to=http://x"));
+        assert_eq!("http://a.example/b/r?to=http://x", resolved(base, "r?to=http://x"));
+        assert_eq!("http://a.example/b/a:b", resolved(base, "./a:b"));
+        assert_eq!("mailto:x@a.example", resolved(base, "mailto:x@a.example"));
+    }
+
+    /// Upstream server on loopback, with its config allowing downloads from it
+    fn upstream() -> (test::TestServer, Config) {
+        let server = test::start(|| App::new()
+            .route("/cat.png", web::get().to(|| HttpResponse::Ok().content_type("image/png").body(LANDSCAPE_PNG)))
+            .route("/page", web::get().to(|| HttpResponse::Ok().content_type("text/html").body("<html/>")))
+            .route("/missing", web::get().to(|| HttpResponse::NotFound().finish()))
+            .route("/moved", web::get().to(|| HttpResponse::Found().header(header::LOCATION, "cat.png").finish()))
+            .route("/loop", web::get().to(|| HttpResponse::Found().header(header::LOCATION, "?again").finish()))
+        );
+        let config = Config { url_allow: vec!["127.0.0.1".parse().unwrap()], ..Config::default() };
+        (server, config)
+    }
+
+    async fn download(server: &test::TestServer, path: &str, config: &Config) -> Result<Image, ApiError> {
+        let message = UrlMessage { name: "cat.png".to_string(), url: format!("http://{}{}", server.addr(), path) };
+        message.try_into_image(config).await
+    }
+
+    #[actix_rt::test]
+    async fn image_is_downloaded_following_redirects() {
+        let (server, config) = upstream();
+
+        assert!(download(&server, "/cat.png", &config).await.is_ok());
+        assert!(download(&server, "/moved", &config).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn download_is_rejected_by_upstream() {
+        let (server, config) = upstream();
+
+        let missing = download(&server, "/missing", &config).await;
+        assert!(matches!(missing, Err(ApiError::UpstreamStatus(404))));
+        let page = download(&server, "/page", &config).await;
+        assert!(matches!(page, Err(ApiError::NotAnImage(content_type)) if content_type == "text/html"));
+    }
+
+    #[actix_rt::test]
+    async fn download_is_limited() {
+        let (server, config) = upstream();
+
+        let large = Config { max_download_size: LANDSCAPE_PNG.len() - 1, ..config.clone() };
+        assert!(matches!(download(&server, "/cat.png", &large).await, Err(ApiError::DownloadTooLarge(_))));
+        let redirects = Config { max_redirects: 2, ..config.clone() };
+        assert!(matches!(download(&server, "/loop", &redirects).await, Err(ApiError::TooManyRedirects(2))));
+        assert!(matches!(download(&server, "/cat.png", &Config::default()).await, Err(ApiError::ForbiddenDestination(_))));
+    }
+}
@@ -0,0 +1,500 @@
+//! Layout of images, their previews and renditions in ['ImageStore']
+use crate::image::{Image, ImageMetadata, ImageName, SUPPORTED_EXTENSIONS};
+use crate::server::{ApiError, ImageStore};
+
+use actix_web::web;
+use serde::Deserialize;
+
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Gallery shared between server's workers as application data
+pub type Storage = web::Data<Gallery>;
+
+/// How images are placed in ['ImageStore'].
+/// In both layouts metadata of image is stored as JSON in "metadata/name",
+/// where name is the one returned by ['Gallery::names']
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    /// Images are stored under their names:
+    /// "name.png", "preview/preview_name.jpeg" and "renditions/64/name.jpeg"
+    ByName,
+    /// Content is stored once under its SHA-256 digest:
+    /// "blobs/digest.png", "preview/digest.jpeg" and "renditions/64/digest.jpeg".
+    /// Names are references to stored content: "refs/name" contains "digest.png"
+    ContentAddressed,
+}
+
+/// Result of storing an image
+#[derive(Debug, PartialEq)]
+pub enum Stored {
+    New,
+    /// The same content is already stored under the digest
+    Duplicate(String),
+}
+
+/// What to do when uploaded image has name of already stored one
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflict {
+    /// Fail with ['ApiError::NameConflict']
+    #[default]
+    Reject,
+    /// Replace stored image and its derived images
+    Overwrite,
+    /// Store image under name with numeric suffix: "name_1.png"
+    Rename,
+}
+
+/// Image-level access to ['ImageStore']
+pub struct Gallery {
+    store: Box<dyn ImageStore>,
+    layout: Layout,
+    /// Widths of renditions generated for every image
+    renditions: Vec<u32>,
+}
+
+/// Makes key of object with `name` and `extension` in `dir`.
+/// As a file path, extension of name is replaced by `extension`
+fn key(dir: &str, name: &str, extension: &str) -> String {
+    let file_name = Path::new(name)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned();
+    if dir.is_empty() {
+        file_name
+    } else {
+        format!("{}/{}", dir, file_name)
+    }
+}
+
+/// Image derived from original and stored alongside it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Derived {
+    /// Small preview: "preview/preview_name.jpeg"
+    Preview,
+    /// Rendition of given width: "renditions/64/name.jpeg"
+    Rendition(u32),
+}
+
+impl Derived {
+    fn dir(&self) -> String {
+        match self {
+            Derived::Preview => "preview".to_string(),
+            Derived::Rendition(width) => format!("renditions/{}", width),
+        }
+    }
+
+    /// Name of derived image of image with `name`
+    fn name(&self, name: &str) -> String {
+        match self {
+            Derived::Preview => "preview_".to_string() + name,
+            Derived::Rendition(_) => name.to_string(),
+        }
+    }
+}
+
+/// Inserts numeric suffix before extension of name
+fn suffixed(name: &str, suffix: usize) -> String {
+    let path = Path::new(name);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("{}_{}.{}",
+                                                 stem.to_string_lossy(), suffix, extension.to_string_lossy()),
+        _ => format!("{}_{}", name, suffix),
+    }
+}
+
+fn name_conflict(name: &str) -> impl Fn(io::Error) -> ApiError + '_ {
+    move |e| match e.kind() {
+        io::ErrorKind::AlreadyExists => ApiError::NameConflict(name.to_string()),
+        _ => ApiError::from(e),
+    }
+}
+
+impl Gallery {
+    pub fn new(store: Box<dyn ImageStore>, layout: Layout, renditions: Vec<u32>) -> Self {
+        Gallery { store, layout, renditions }
+    }
+
+    /// Stores original image under its name together with its metadata.
+    /// If the name is taken, `conflict` policy is applied,
+    /// so image may be renamed
+    ///
+    /// # Errors
+    /// If image with such name already exists and policy is ['Conflict::Reject']
+    /// If backend fails to write data
+    ///
+    pub fn put(&self, image: &mut Image, conflict: Conflict) -> Result<Stored, ApiError> {
+        let stored = self.put_original(image, conflict)?;
+        if let Err(e) = self.put_metadata(image) {
+            if let Err(removal) = self.delete(image.name()) {
+                error!("Failed to remove {} without metadata: {}", image.name(), removal);
+            }
+            return Err(e);
+        }
+        Ok(stored)
+    }
+
+    fn put_original(&self, image: &mut Image, conflict: Conflict) -> Result<Stored, ApiError> {
+        if self.exists(image.name())? {
+            match conflict {
+                Conflict::Reject => return Err(ApiError::NameConflict(image.name().to_string())),
+                Conflict::Overwrite => self.delete(image.name())?,
+                Conflict::Rename => {
+                    let mut suffix = 1;
+                    while self.exists(&suffixed(image.name(), suffix))? {
+                        suffix += 1;
+                    }
+                    let name = ImageName::new(&suffixed(image.name(), suffix))?;
+                    image.rename(name);
+                }
+            }
+        }
+
+        match self.layout {
+            Layout::ByName => {
+                self.store.put(&key("", image.name(), image.extension()), image.data())
+                    .map_err(name_conflict(image.name()))?;
+                Ok(Stored::New)
+            }
+            Layout::ContentAddressed => {
+                let reference = format!("refs/{}", image.name());
+                let digest = image.digest();
+                let blob = key("", &digest, image.extension());
+                let stored = if self.store.exists(&format!("blobs/{}", blob))? {
+                    Stored::Duplicate(digest)
+                } else {
+                    match self.store.put(&format!("blobs/{}", blob), image.data()) {
+                        Ok(()) => Stored::New,
+                        // Identical content was stored concurrently under the same digest
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Stored::Duplicate(digest),
+                        Err(e) => return Err(e.into()),
+                    }
+                };
+                self.store.put(&reference, blob.as_bytes())
+                    .map_err(name_conflict(image.name()))?;
+                Ok(stored)
+            }
+        }
+    }
+
+    /// Stores metadata of stored `image`, replacing metadata left by removed image
+    fn put_metadata(&self, image: &Image) -> Result<(), ApiError> {
+        let key = self.metadata_key(image.name());
+        if self.store.exists(&key)? {
+            self.store.delete(&key)?;
+        }
+        let metadata = serde_json::to_vec(image.metadata())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.store.put(&key, &metadata)?;
+        Ok(())
+    }
+
+    /// Loads metadata of image with `name`.
+    /// Metadata of images stored without it is red from their content
+    ///
+    /// # Errors
+    /// If name is not valid
+    /// If there is no stored image with such name
+    /// If metadata cannot be red
+    ///
+    pub fn metadata(&self, name: &str) -> Result<ImageMetadata, ApiError> {
+        let name = ImageName::new(name)?;
+        if !self.exists(name.as_str())? {
+            return Err(ApiError::NotFound(name.to_string()));
+        }
+        let key = self.metadata_key(name.as_str());
+        if !self.store.exists(&key)? {
+            return Ok(self.get(name.as_str())?.metadata().clone());
+        }
+        let metadata = serde_json::from_slice(&self.store.get(&key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(metadata)
+    }
+
+    /// Checks whether image with such name is stored
+    pub fn exists(&self, name: &str) -> Result<bool, ApiError> {
+        let name = ImageName::new(name)?;
+        let name = name.as_str();
+        match self.layout {
+            Layout::ByName => Ok(self.find("", name)?.is_some()),
+            Layout::ContentAddressed => Ok(self.store.exists(&format!("refs/{}", name))?),
+        }
+    }
+
+    /// Stores image derived from `original` image
+    ///
+    /// # Errors
+    /// If derived image already exists
+    /// If backend fails to write data
+    ///
+    pub fn put_derived(&self, original: &Image, derived: Derived, image: &Image) -> Result<(), ApiError> {
+        let key = key(&derived.dir(), &self.derived_stem(original, derived), image.extension());
+        self.store.put(&key, image.data())?;
+        Ok(())
+    }
+
+    /// Checks whether image derived from `original` image is already stored
+    pub fn has_derived(&self, original: &Image, derived: Derived) -> Result<bool, ApiError> {
+        self.find(&derived.dir(), &self.derived_stem(original, derived))
+            .map(|key| key.is_some())
+    }
+
+    /// Loads original image by its name
+    ///
+    /// # Errors
+    /// If name is not valid
+    /// If there is no stored image with such name
+    /// If data cannot be red or it is not a supported image
+    ///
+    pub fn get(&self, name: &str) -> Result<Image, ApiError> {
+        let name = ImageName::new(name)?;
+        let key = match self.layout {
+            Layout::ByName => self.find("", name.as_str())?,
+            Layout::ContentAddressed => self.resolve(name.as_str())?
+                .map(|blob| format!("blobs/{}", blob)),
+        };
+        self.load(key, name)
+    }
+
+    /// Loads image derived from image with `name`.
+    /// Loaded image has the name of original
+    ///
+    /// # Errors
+    /// If name is not valid
+    /// If there is no stored image with such name or it has no such derived image
+    /// If data cannot be red or it is not a supported image
+    ///
+    pub fn get_derived(&self, name: &str, derived: Derived) -> Result<Image, ApiError> {
+        let name = ImageName::new(name)?;
+        let stem = match self.layout {
+            Layout::ByName => Some(derived.name(name.as_str())),
+            Layout::ContentAddressed => self.resolve(name.as_str())?
+                .map(|blob| Self::stem(&blob)),
+        };
+        let key = match stem {
+            Some(stem) => self.find(&derived.dir(), &stem)?,
+            None => None,
+        };
+        self.load(key, name)
+    }
+
+    /// Stores `variant` of `original` image, produced on demand
+    ///
+    /// # Errors
+    /// If the variant already exists
+    /// If backend fails to write data
+    ///
+    pub fn put_cached(&self, original: &Image, variant: &str, image: &Image) -> Result<(), ApiError> {
+        let stem = match self.layout {
+            Layout::ByName => Self::stem(original.name()),
+            Layout::ContentAddressed => original.digest(),
+        };
+        self.store.put(&key(&self.cache_dir(&stem), variant, image.extension()), image.data())?;
+        Ok(())
+    }
+
+    /// Loads `variant` of image with `name`, previously stored by ['Gallery::put_cached'].
+    /// Returns None if variant is not cached
+    pub fn get_cached(&self, name: &str, variant: &str) -> Result<Option<Image>, ApiError> {
+        let name = ImageName::new(name)?;
+        let stem = match self.layout {
+            Layout::ByName => Some(Self::stem(name.as_str())),
+            Layout::ContentAddressed => self.resolve(name.as_str())?
+                .map(|blob| Self::stem(&blob)),
+        };
+        let key = match stem {
+            Some(stem) => self.find(&self.cache_dir(&stem), variant)?,
+            None => None,
+        };
+        match key {
+            Some(key) => Ok(Some(self.load(Some(key), name)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Kinds of images derived from every stored image
+    pub fn derived(&self) -> Vec<Derived> {
+        let mut derived = vec![Derived::Preview];
+        derived.extend(self.renditions.iter().map(|width| Derived::Rendition(*width)));
+        derived
+    }
+
+    /// Returns names of all stored images with their creation time.
+    /// Stray objects, which are not images with valid names, like ".gitkeep", are skipped
+    pub fn names(&self) -> Result<Vec<(String, SystemTime)>, ApiError> {
+        let mut names = vec![];
+        match self.layout {
+            Layout::ByName => for file_name in self.store.list("")? {
+                let extension = Path::new(&file_name).extension()
+                    .map(|extension| extension.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let name = Self::stem(&file_name);
+                if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) || ImageName::new(&name).is_err() {
+                    warn!("Stray object {} is not listed", file_name);
+                    continue;
+                }
+                names.push((name, self.store.created(&file_name)?));
+            },
+            Layout::ContentAddressed => for name in self.store.list("refs")? {
+                if ImageName::new(&name).is_err() {
+                    warn!("Stray reference {} is not listed", name);
+                    continue;
+                }
+                let created = self.store.created(&format!("refs/{}", name))?;
+                names.push((name, created));
+            },
+        }
+        Ok(names)
+    }
+
+    /// Removes image together with its metadata, preview and renditions.
+    /// Either all of them are removed or none
+    ///
+    /// # Errors
+    /// If name is not valid
+    /// If there is no stored image with such name
+    /// If backend fails to remove data
+    ///
+    pub fn delete(&self, name: &str) -> Result<(), ApiError> {
+        let name = ImageName::new(name)?;
+        let name = name.as_str();
+        let keys = match self.layout {
+            Layout::ByName => {
+                let original = self.find("", name)?
+                    .ok_or_else(|| ApiError::NotFound(name.to_string()))?;
+                let mut keys = vec![original];
+                for derived in self.derived() {
+                    keys.extend(self.find(&derived.dir(), &derived.name(name))?);
+                }
+                keys.extend(self.cached_keys(&Self::stem(name))?);
+                keys.extend(self.stored_metadata_key(name)?);
+                keys
+            }
+            Layout::ContentAddressed => {
+                let blob = self.resolve(name)?
+                    .ok_or_else(|| ApiError::NotFound(name.to_string()))?;
+                let mut keys = vec![format!("refs/{}", name)];
+                // Content is shared between all names referencing it
+                if self.references(&blob)? == 1 {
+                    keys.push(format!("blobs/{}", blob));
+                    for derived in self.derived() {
+                        keys.extend(self.find(&derived.dir(), &Self::stem(&blob))?);
+                    }
+                    keys.extend(self.cached_keys(&Self::stem(&blob))?);
+                }
+                keys.extend(self.stored_metadata_key(name)?);
+                keys
+            }
+        };
+        self.delete_all(&keys)
+    }
+
+    /// Removes objects by `keys`. If some removal fails,
+    /// already removed objects are restored
+    fn delete_all(&self, keys: &[String]) -> Result<(), ApiError> {
+        let mut backup = Vec::with_capacity(keys.len());
+        for key in keys {
+            backup.push((key, self.store.get(key)?));
+        }
+        for (removed, (key, _)) in backup.iter().enumerate() {
+            if let Err(e) = self.store.delete(key) {
+                for (key, data) in &backup[..removed] {
+                    if let Err(e) = self.store.put(key, data) {
+                        error!("Failed to restore {}: {}", key, e);
+                    }
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts names referencing `blob`
+    fn references(&self, blob: &str) -> Result<usize, ApiError> {
+        let mut count = 0;
+        for name in self.store.list("refs")? {
+            if self.store.get(&format!("refs/{}", name))? == blob.as_bytes() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn load(&self, key: Option<String>, name: ImageName) -> Result<Image, ApiError> {
+        match key {
+            Some(key) => Ok(Image::new(name, self.store.get(&key)?)?),
+            None => Err(ApiError::NotFound(name.to_string())),
+        }
+    }
+
+    /// Finds key of object with `stem` in `dir`.
+    /// The key's suffix is resolved by trying every supported extension
+    fn find(&self, dir: &str, stem: &str) -> Result<Option<String>, ApiError> {
+        for extension in SUPPORTED_EXTENSIONS {
+            let key = key(dir, stem, extension);
+            if self.store.exists(&key)? {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns file name of blob, referenced by `name`
+    fn resolve(&self, name: &str) -> Result<Option<String>, ApiError> {
+        let reference = format!("refs/{}", name);
+        if !self.store.exists(&reference)? {
+            return Ok(None);
+        }
+        let blob = String::from_utf8_lossy(&self.store.get(&reference)?).into_owned();
+        Ok(Some(blob))
+    }
+
+    /// Directory of variants of image with `stem`, produced on demand
+    fn cache_dir(&self, stem: &str) -> String {
+        format!("cache/{}", stem)
+    }
+
+    fn cached_keys(&self, stem: &str) -> Result<Vec<String>, ApiError> {
+        let dir = self.cache_dir(stem);
+        Ok(self.store.list(&dir)?
+            .into_iter()
+            .map(|variant| format!("{}/{}", dir, variant))
+            .collect())
+    }
+
+    /// Key of metadata of image with `name`.
+    /// Images stored by name are identified by their names without extension
+    fn metadata_key(&self, name: &str) -> String {
+        match self.layout {
+            Layout::ByName => format!("metadata/{}", Self::stem(name)),
+            Layout::ContentAddressed => format!("metadata/{}", name),
+        }
+    }
+
+    /// Key of metadata of image with `name`, if it is stored
+    fn stored_metadata_key(&self, name: &str) -> Result<Option<String>, ApiError> {
+        let key = self.metadata_key(name);
+        if self.store.exists(&key)? {
+            Ok(Some(key))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Name of derived image without extension
+    fn derived_stem(&self, original: &Image, derived: Derived) -> String {
+        match self.layout {
+            Layout::ByName => derived.name(original.name()),
+            Layout::ContentAddressed => original.digest(),
+        }
+    }
+
+    fn stem(file_name: &str) -> String {
+        Path::new(file_name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,181 @@
+//! Registry of upload requests processed in background
+use crate::server::ApiError;
+use crate::server::response::ResponseMessage;
+use crate::server::request_id::unique_id;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Images are being extracted from request
+    Pending,
+    /// Images are being processed
+    Running,
+    Done,
+}
+
+/// Progress of job. `results` are in order of images in request,
+/// they have the same shape as response of synchronous request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    /// Number of images in request, known after extraction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    pub results: Vec<ResponseMessage>,
+}
+
+struct Job {
+    status: JobStatus,
+    finished: Option<Instant>,
+}
+
+/// Jobs shared between server's workers.
+/// Finished jobs are forgotten after `ttl`.
+/// At most `max_unfinished` jobs are pending or running at once
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    ttl: Duration,
+    max_unfinished: usize,
+    /// Seconds in `Retry-After` header of uploads, rejected while `max_unfinished` jobs are unfinished
+    retry_after: u64,
+}
+
+impl Jobs {
+    pub fn new(ttl: Duration, max_unfinished: usize, retry_after: u64) -> Self {
+        Jobs {
+            jobs: Mutex::new(HashMap::new()),
+            ttl,
+            max_unfinished,
+            retry_after,
+        }
+    }
+
+    /// Registers new pending job and returns its id.
+    /// Ids are not sequential, so other clients cannot guess them
+    ///
+    /// # Errors
+    /// ['ApiError::Overloaded'] if there are too many unfinished jobs
+    ///
+    pub fn create(&self) -> Result<String, ApiError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let ttl = self.ttl;
+        jobs.retain(|_, job| job.finished.is_none_or(|finished| finished.elapsed() < ttl));
+        if jobs.values().filter(|job| job.finished.is_none()).count() >= self.max_unfinished {
+            return Err(ApiError::Overloaded(self.retry_after));
+        }
+
+        let id = unique_id();
+        jobs.insert(id.clone(), Job {
+            status: JobStatus { id: id.clone(), state: JobState::Pending, total: None, results: vec![] },
+            finished: None,
+        });
+        Ok(id)
+    }
+
+    /// Marks job as running with `total` images
+    pub fn start(&self, id: &str, total: usize) {
+        self.update(id, |job| {
+            job.status.state = JobState::Running;
+            job.status.total = Some(total);
+        });
+    }
+
+    /// Appends result of the next image
+    pub fn record(&self, id: &str, result: ResponseMessage) {
+        self.update(id, |job| job.status.results.push(result));
+    }
+
+    pub fn finish(&self, id: &str) {
+        self.update(id, |job| {
+            job.status.state = JobState::Done;
+            job.finished = Some(Instant::now());
+        });
+    }
+
+    /// Returns current status of job
+    ///
+    /// # Errors
+    /// If there is no job with such id or it is expired
+    ///
+    pub fn status(&self, id: &str) -> Result<JobStatus, ApiError> {
+        self.jobs.lock().unwrap()
+            .get(id)
+            .map(|job| job.status.clone())
+            .ok_or_else(|| ApiError::JobNotFound(id.to_string()))
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: &str, f: F) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+
+    fn jobs(max_unfinished: usize) -> Jobs {
+        Jobs::new(Duration::from_secs(60), max_unfinished, 5)
+    }
+
+    #[test]
+    fn job_goes_from_pending_through_running_to_done() {
+        let jobs = jobs(4);
+        let id = jobs.create().unwrap();
+        let status = jobs.status(&id).unwrap();
+        assert_eq!((JobState::Pending, None), (status.state, status.total));
+
+        jobs.start(&id, 2);
+        jobs.record(&id, ResponseMessage::new(StatusCode::OK.as_u16(), "Stored".to_string()));
+        let status = jobs.status(&id).unwrap();
+        assert_eq!((JobState::Running, Some(2), 1), (status.state, status.total, status.results.len()));
+
+        jobs.record(&id, ApiError::NameConflict("cat.png".to_string()).into());
+        jobs.finish(&id);
+        let status = jobs.status(&id).unwrap();
+        assert_eq!(JobState::Done, status.state);
+        assert_eq!(vec![200, 409], status.results.iter().map(|result| result.code).collect::<Vec<_>>());
+        assert_eq!(Some("name_conflict"), status.results[1].kind.as_deref());
+    }
+
+    #[test]
+    fn unknown_job_is_not_found() {
+        assert!(matches!(jobs(4).status("unknown"), Err(ApiError::JobNotFound(id)) if id == "unknown"));
+    }
+
+    #[test]
+    fn unfinished_jobs_are_limited() {
+        let jobs = jobs(2);
+        let first = jobs.create().unwrap();
+        let second = jobs.create().unwrap();
+        assert_ne!(first, second);
+
+        assert!(matches!(jobs.create(), Err(ApiError::Overloaded(5))));
+
+        jobs.finish(&first);
+        assert!(jobs.create().is_ok());
+    }
+
+    #[test]
+    fn finished_jobs_expire() {
+        let jobs = Jobs::new(Duration::from_secs(0), 4, 5);
+        let finished = jobs.create().unwrap();
+        let running = jobs.create().unwrap();
+        jobs.finish(&finished);
+
+        jobs.create().unwrap();
+
+        assert!(jobs.status(&finished).is_err());
+        assert!(jobs.status(&running).is_ok());
+    }
+}
@@ -0,0 +1,133 @@
+//! Paginated listing of stored images
+use crate::server::{ApiError, Gallery};
+
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    Name,
+    Time,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Query parameters of listing request.
+/// `cursor` is `next_cursor` of previous page
+#[derive(Deserialize, Debug)]
+pub struct ListQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub sort: Option<Sort>,
+    pub order: Option<Order>,
+}
+
+/// Description of stored image
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageInfo {
+    pub name: String,
+    pub format: String,
+    pub size: usize,
+    pub width: u32,
+    pub height: u32,
+    /// Unix timestamp in seconds
+    pub created: u64,
+    pub preview: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page {
+    pub images: Vec<ImageInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Position of image in sorted listing.
+/// Time is zero when images are sorted by name
+type Position = (u64, String);
+
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn encode_cursor(position: &Position) -> String {
+    format!("{}:{}", position.0, position.1)
+}
+
+fn decode_cursor(cursor: &str) -> Result<Position, ApiError> {
+    let mut parts = cursor.splitn(2, ':');
+    match (parts.next().map(str::parse), parts.next()) {
+        (Some(Ok(time)), Some(name)) => Ok((time, name.to_string())),
+        _ => Err(ApiError::InvalidCursor),
+    }
+}
+
+/// Makes page of images stored in `gallery`.
+/// Images are described by their stored metadata, their content is not red
+///
+/// # Errors
+/// If cursor is malformed
+/// If metadata cannot be red from storage
+///
+pub fn list(gallery: &Gallery, query: ListQuery) -> Result<Page, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let sort = query.sort.unwrap_or(Sort::Name);
+    let order = query.order.unwrap_or(Order::Asc);
+    let after = match &query.cursor {
+        Some(cursor) => Some(decode_cursor(cursor)?),
+        None => None,
+    };
+
+    let mut entries: Vec<(Position, u64)> = gallery.names()?
+        .into_iter()
+        .map(|(name, created)| {
+            let created = timestamp(created);
+            match sort {
+                Sort::Name => ((0, name), created),
+                Sort::Time => ((created, name), created),
+            }
+        })
+        .collect();
+    entries.sort();
+    if order == Order::Desc {
+        entries.reverse();
+    }
+    if let Some(after) = after {
+        entries.retain(|(position, _)| match order {
+            Order::Asc => *position > after,
+            Order::Desc => *position < after,
+        });
+    }
+
+    let next_cursor = if entries.len() > limit {
+        Some(encode_cursor(&entries[limit - 1].0))
+    } else {
+        None
+    };
+
+    let mut images = vec![];
+    for ((_, name), created) in entries.into_iter().take(limit) {
+        let metadata = gallery.metadata(&name)?;
+        images.push(ImageInfo {
+            format: metadata.format.extension().to_string(),
+            size: metadata.byte_size,
+            width: metadata.width,
+            height: metadata.height,
+            created,
+            preview: format!("/images/{}/preview", name),
+            name,
+        });
+    }
+    Ok(Page { images, next_cursor })
+}
@@ -1,9 +1,28 @@
 mod store;
+mod gallery;
+mod config;
+mod listing;
+mod resize;
+mod negotiation;
+mod pool;
+mod response;
+mod request_id;
+mod jobs;
 mod routes;
 mod extractor;
+mod egress;
 mod api_error;
 
 pub use routes::init_routes;
 pub use extractor::{SupportedRequest, UrlMessage, JsonMessage, ApiUrlRequest, ApiJsonRequest, MultipartField};
 pub use api_error::ApiError;
+pub use response::{ErrorBody, ResponseMessage};
+pub use request_id::RequestId;
+pub use store::{ImageStore, FsStore, MemoryStore};
+pub use gallery::{Gallery, Layout, Stored, Conflict, Derived, Storage};
+pub use config::Config;
+pub use egress::{Destination, Network};
+pub use negotiation::FormatPolicy;
+pub use pool::BlockingPool;
+pub use jobs::{Jobs, JobStatus, JobState};
 
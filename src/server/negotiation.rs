@@ -0,0 +1,179 @@
+//! Choice of output format of derived images:
+//! by server's policy, by `format` query parameter or by `Accept` header
+use crate::image::{ImageFormat, OutputFormat, OUTPUT_FORMATS};
+use crate::server::ApiError;
+
+use std::str::FromStr;
+
+/// Format of previews and renditions generated on upload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormatPolicy {
+    /// Follows original: jpeg and bmp become jpeg, webp stays webp
+    /// if backend can encode it, other formats become png to keep transparency
+    Auto,
+    Fixed(OutputFormat),
+}
+
+impl FormatPolicy {
+    /// Output format for image in `source` format
+    pub fn format_for(&self, source: ImageFormat) -> OutputFormat {
+        match self {
+            FormatPolicy::Fixed(format) => *format,
+            FormatPolicy::Auto => match source {
+                ImageFormat::Jpeg | ImageFormat::Bmp => OutputFormat::Jpeg,
+                ImageFormat::Webp if OutputFormat::Webp.is_supported() => OutputFormat::Webp,
+                _ => OutputFormat::Png,
+            },
+        }
+    }
+}
+
+impl FromStr for FormatPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(FormatPolicy::Auto),
+            other => other.parse().map(FormatPolicy::Fixed),
+        }
+    }
+}
+
+/// Parses `format` query parameter
+///
+/// # Errors
+/// If value is not a format supported by enabled backend
+///
+pub fn parse_format(value: &Option<String>) -> Result<Option<OutputFormat>, ApiError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.parse::<OutputFormat>() {
+        Ok(format) if format.is_supported() => Ok(Some(format)),
+        _ => {
+            let formats = OUTPUT_FORMATS.iter()
+                .map(|format| format.to_string())
+                .collect::<Vec<_>>();
+            Err(ApiError::InvalidParameter(format!("format must be one of {}", formats.join(", "))))
+        }
+    }
+}
+
+/// Media range of `Accept` header with its weight
+struct MediaRange {
+    range: String,
+    quality: f32,
+}
+
+fn media_ranges(accept: &str) -> Vec<MediaRange> {
+    accept.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim().to_lowercase();
+            let mut quality = 1.0;
+            for parameter in parts {
+                if let Some(value) = parameter.trim().strip_prefix("q=") {
+                    // Ranges with malformed weight are ignored
+                    quality = value.parse::<f32>().ok()
+                        .filter(|quality| (0.0..=1.0).contains(quality))?;
+                }
+            }
+            Some(MediaRange { range, quality })
+        })
+        .filter(|media_range| !media_range.range.is_empty())
+        .collect()
+}
+
+/// Weight of `format` given by the most specific matching range:
+/// "image/png", then "image/*", then "*/*"
+fn weight(ranges: &[MediaRange], format: OutputFormat) -> f32 {
+    let specific = [format.mime_type(), "image/*", "*/*"];
+    specific.iter()
+        .filter_map(|range| ranges.iter().find(|media_range| media_range.range == *range))
+        .map(|media_range| media_range.quality)
+        .next()
+        .unwrap_or(0.0)
+}
+
+/// Chooses format by `Accept` header among formats of enabled backend.
+/// `preferred` format wins ties and is chosen if header is missing.
+/// Formats with zero weight, like "image/jpeg;q=0", are not acceptable
+///
+/// # Errors
+/// If none of formats is acceptable
+///
+pub fn negotiate(accept: Option<&str>, preferred: OutputFormat) -> Result<OutputFormat, ApiError> {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return Ok(preferred),
+    };
+    let ranges = media_ranges(accept);
+    let mut chosen = (preferred, weight(&ranges, preferred));
+    for format in OUTPUT_FORMATS {
+        let weight = weight(&ranges, *format);
+        if weight > chosen.1 {
+            chosen = (*format, weight);
+        }
+    }
+    if chosen.1 > 0.0 {
+        Ok(chosen.0)
+    } else {
+        Err(ApiError::NotAcceptable(accept.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+
+    #[test]
+    fn media_ranges_are_parsed_with_weights() {
+        let ranges = media_ranges("Image/PNG ; q=0.3, */*, image/webp;q=2, image/gif;q=x, ,text/html;level=1");
+        let parsed = ranges.iter()
+            .map(|media_range| (media_range.range.as_str(), media_range.quality))
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![("image/png", 0.3), ("*/*", 1.0), ("text/html", 1.0)], parsed);
+    }
+
+    #[test]
+    fn missing_header_chooses_preferred_format() {
+        assert_eq!(OutputFormat::Png, negotiate(None, OutputFormat::Png).unwrap());
+    }
+
+    #[test]
+    fn the_heaviest_format_is_chosen() {
+        assert_eq!(OutputFormat::Png, negotiate(Some("image/png"), OutputFormat::Jpeg).unwrap());
+        assert_eq!(OutputFormat::Jpeg,
+                   negotiate(Some("image/png;q=0.5, image/jpeg;q=0.8"), OutputFormat::Png).unwrap());
+        // Range with malformed weight is ignored
+        assert_eq!(OutputFormat::Jpeg, negotiate(Some("image/png;q=high, image/jpeg"), OutputFormat::Png).unwrap());
+    }
+
+    #[test]
+    fn wildcards_keep_preferred_format() {
+        assert_eq!(OutputFormat::Png, negotiate(Some("image/*"), OutputFormat::Png).unwrap());
+        assert_eq!(OutputFormat::Jpeg, negotiate(Some("*/*"), OutputFormat::Jpeg).unwrap());
+        assert_eq!(OutputFormat::Png, negotiate(Some("image/png, */*;q=0.1"), OutputFormat::Jpeg).unwrap());
+    }
+
+    #[test]
+    fn zero_weight_makes_format_not_acceptable() {
+        assert_eq!(OutputFormat::Png, negotiate(Some("image/*, image/jpeg;q=0"), OutputFormat::Jpeg).unwrap());
+        assert_eq!(OutputFormat::Png, negotiate(Some("image/jpeg;q=0, image/png;q=0.1"), OutputFormat::Jpeg).unwrap());
+    }
+
+    #[test]
+    fn unacceptable_formats_are_rejected_with_406() {
+        for accept in &["image/jpeg;q=0", "text/html", "image/*;q=0", "*/*;q=0.0"] {
+            match negotiate(Some(accept), OutputFormat::Jpeg) {
+                Err(e @ ApiError::NotAcceptable(_)) => assert_eq!(StatusCode::NOT_ACCEPTABLE, e.status_code()),
+                other => panic!("{} is negotiated as {:?}", accept, other),
+            }
+        }
+    }
+}
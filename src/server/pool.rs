@@ -0,0 +1,124 @@
+//! Bounded pool of threads for blocking work:
+//! image transformations and storage access.
+//! Keeps async workers of the server free
+use crate::server::ApiError;
+
+use futures::channel::oneshot;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Pool of `size` threads with queue of at most `queue_depth` waiting jobs.
+/// Jobs are rejected with ['ApiError::Overloaded'] when the queue is full
+pub struct BlockingPool {
+    sender: Mutex<SyncSender<Job>>,
+    /// Seconds in `Retry-After` header of requests, whose jobs did not fit into the queue
+    retry_after: u64,
+}
+
+impl BlockingPool {
+    /// Starts pool's threads
+    ///
+    /// # Panics
+    /// If thread cannot be spawned
+    ///
+    pub fn new(size: usize, queue_depth: usize, retry_after: u64) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..size.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("blocking-{}", index))
+                .spawn(move || loop {
+                    // Lock is released before job is run
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // Panic of job must not kill the thread. Its result is dropped,
+                        // so waiting request fails
+                        Ok(job) => if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!("Blocking job panicked");
+                        },
+                        // Pool is dropped
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn blocking thread");
+        }
+        BlockingPool { sender: Mutex::new(sender), retry_after }
+    }
+
+    /// Runs `job` on pool's thread and returns its result
+    ///
+    /// # Errors
+    /// If queue of the pool is full
+    /// If job fails or panics
+    ///
+    pub async fn run<F, T>(&self, job: F) -> Result<T, ApiError>
+        where F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+              T: Send + 'static
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // Receiver is dropped if request is canceled
+            let _ = sender.send(job());
+        });
+
+        self.sender.lock().unwrap()
+            .try_send(job)
+            .map_err(|e| match e {
+                TrySendError::Full(_) => ApiError::Overloaded(self.retry_after),
+                TrySendError::Disconnected(_) => ApiError::BlockingCanceled,
+            })?;
+
+        receiver.await
+            .map_err(|_| ApiError::BlockingCanceled)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::{header, StatusCode};
+    use actix_web::ResponseError;
+    use futures::FutureExt;
+
+    #[test]
+    fn full_queue_rejects_jobs_with_retry_after() {
+        let pool = BlockingPool::new(1, 1, 7);
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+
+        // Jobs are queued on the first poll and run after their futures are dropped
+        assert!(pool.run(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+            Ok(())
+        }).now_or_never().is_none());
+        running.recv().unwrap();
+        assert!(pool.run(|| Ok(())).now_or_never().is_none());
+
+        let rejected = pool.run(|| Ok(())).now_or_never().unwrap();
+        release.send(()).unwrap();
+
+        let e = rejected.unwrap_err();
+        assert!(matches!(e, ApiError::Overloaded(7)));
+        let response = e.error_response();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("7", response.headers().get(header::RETRY_AFTER).unwrap());
+    }
+
+    #[test]
+    fn panicking_job_does_not_stop_pool() {
+        let pool = BlockingPool::new(1, 1, 7);
+
+        let panicked = futures::executor::block_on(pool.run(|| -> Result<(), ApiError> { panic!("job failed") }));
+        assert!(matches!(panicked, Err(ApiError::BlockingCanceled)));
+
+        assert_eq!(Ok(2), futures::executor::block_on(pool.run(|| Ok(1 + 1))).map_err(|e| e.to_string()));
+    }
+}
@@ -0,0 +1,82 @@
+//! Identifiers of requests, reported in error bodies and `X-Request-Id` header
+use crate::server::ApiError;
+use crate::server::response::ErrorBody;
+
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// Longest id accepted from client
+const MAX_LENGTH: usize = 64;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns 32 hex digits, unique within the process and hard to guess
+pub fn unique_id() -> String {
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!("{:x}", Sha256::digest(format!("{}:{}", counter, nanos).as_bytes()))[..32].to_string()
+}
+
+/// Id of request. Taken from `X-Request-Id` header of request if it is valid,
+/// so that requests can be traced through proxies, otherwise generated
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Assigns id to request before it is handled
+    pub fn assign(request: &ServiceRequest) -> RequestId {
+        let id = request.headers().get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_LENGTH)
+            .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .map(str::to_string)
+            .unwrap_or_else(unique_id);
+        let id = RequestId(id);
+        request.extensions_mut().insert(id.clone());
+        id
+    }
+
+    /// Adds id to response. Error responses are replaced with ['ErrorBody'] containing the id
+    pub fn attach(&self, mut response: ServiceResponse) -> ServiceResponse {
+        let error = response.response().error().map(|e| match e.as_error::<ApiError>() {
+            Some(e) => e.response(Some(self)),
+            None => {
+                let status = response.status();
+                HttpResponse::build(status)
+                    .json(ErrorBody::generic(status, &e.to_string(), Some(self)))
+            }
+        });
+        if let Some(error) = error {
+            response = response.into_response(error);
+        }
+        if let Ok(id) = HeaderValue::from_str(&self.0) {
+            response.headers_mut().insert(HeaderName::from_static(REQUEST_ID), id);
+        }
+        response
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let id = request.extensions().get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId(unique_id()));
+        ready(Ok(id))
+    }
+}
@@ -0,0 +1,268 @@
+//! Resizing of stored images on demand
+use crate::image::{Encoding, Fit, Image, OutputFormat, Rgb};
+use crate::server::{ApiError, Config, Derived, Gallery};
+use crate::server::negotiation::{self, negotiate};
+
+use serde::Deserialize;
+
+/// Query parameters of download request.
+/// Parsed manually to report invalid values as ['ApiError::InvalidParameter']
+#[derive(Deserialize, Debug, Default)]
+pub struct ResizeQuery {
+    pub w: Option<String>,
+    pub h: Option<String>,
+    pub fit: Option<String>,
+    /// Colour of padding for `fit=pad` as hex "rrggbb"
+    pub background: Option<String>,
+    /// Output format, negotiated by `Accept` header if missing
+    pub format: Option<String>,
+}
+
+impl ResizeQuery {
+    /// Checks whether any transformation is requested
+    pub fn is_empty(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.fit.is_none()
+            && self.background.is_none() && self.format.is_none()
+    }
+}
+
+/// Validated parameters of resizing.
+/// Missing width or height are calculated from original's aspect ratio
+#[derive(Debug, PartialEq)]
+struct Resize {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    format: Option<OutputFormat>,
+}
+
+fn invalid(parameter: &str, expected: &str) -> ApiError {
+    ApiError::InvalidParameter(format!("{} must be {}", parameter, expected))
+}
+
+fn parse_background(value: &Option<String>) -> Result<Rgb, ApiError> {
+    let value = match value {
+        Some(value) => value.trim_start_matches('#'),
+        None => return Ok(Rgb(255, 255, 255)),
+    };
+    match u32::from_str_radix(value, 16) {
+        Ok(colour) if value.len() == 6 && value.chars().all(|c| c.is_ascii_hexdigit()) =>
+            Ok(Rgb((colour >> 16) as u8, (colour >> 8) as u8, colour as u8)),
+        _ => Err(invalid("background", "hex colour rrggbb")),
+    }
+}
+
+fn parse_dimension(parameter: &str, value: &Option<String>) -> Result<Option<u32>, ApiError> {
+    match value {
+        Some(value) => match value.parse::<u32>() {
+            Ok(dimension) if dimension > 0 => Ok(Some(dimension)),
+            _ => Err(invalid(parameter, "positive integer")),
+        },
+        None => Ok(None),
+    }
+}
+
+impl Resize {
+    fn parse(query: &ResizeQuery) -> Result<Self, ApiError> {
+        let fit = match query.fit.as_deref() {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") => Fit::Cover,
+            Some("fill") => Fit::Fill,
+            Some("pad") => Fit::Pad(parse_background(&query.background)?),
+            _ => return Err(invalid("fit", "one of cover, contain, fill, pad")),
+        };
+        Ok(Resize {
+            width: parse_dimension("w", &query.w)?,
+            height: parse_dimension("h", &query.h)?,
+            fit,
+            format: negotiation::parse_format(&query.format)?,
+        })
+    }
+
+    /// Name of cached variant, like "100x50_padffffff_png3"
+    fn variant(&self, width: u32, height: u32, encoding: Encoding) -> String {
+        let fit = match self.fit {
+            Fit::Fill => "fill".to_string(),
+            Fit::Contain => "contain".to_string(),
+            Fit::Cover => "cover".to_string(),
+            Fit::Pad(Rgb(red, green, blue)) => format!("pad{:02x}{:02x}{:02x}", red, green, blue),
+        };
+        format!("{}x{}_{}_{}{}", width, height, fit, encoding.format, encoding.quality)
+    }
+
+    /// Target size for `original`, clamped to configured limits.
+    /// Limits bound memory of transformation too, since backends crop excess of cover
+    /// from original before resizing and never scale the whole original beyond target size
+    fn size(&self, original: &Image, config: &Config) -> (u32, u32) {
+        let (original_width, original_height) = original.dimensions();
+        let original_width = original_width.max(1) as u64;
+        let original_height = original_height.max(1) as u64;
+        let max_width = config.max_resize_width as u64;
+        let max_height = config.max_resize_height as u64;
+
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width as u64, height as u64),
+            (Some(width), None) => {
+                let width = (width as u64).min(max_width);
+                (width, width * original_height / original_width)
+            }
+            (None, Some(height)) => {
+                let height = (height as u64).min(max_height);
+                (height * original_width / original_height, height)
+            }
+            (None, None) => (original_width, original_height),
+        };
+        (width.min(max_width).max(1) as u32, height.min(max_height).max(1) as u32)
+    }
+}
+
+/// Returns stored image with `name`, resized by query parameters.
+/// Without `format` parameter, output format is negotiated by `accept` header,
+/// configured format of derived images is preferred.
+/// Resized images are cached in gallery
+///
+/// # Errors
+/// If query parameters are invalid
+/// If there is no image with given name
+/// If none of output formats is acceptable
+/// If transformation failed
+///
+pub fn resized(gallery: &Gallery, name: &str, query: &ResizeQuery, accept: Option<&str>,
+               config: &Config) -> Result<Image, ApiError> {
+    let resize = Resize::parse(query)?;
+    let original = gallery.get(name)?;
+    let (width, height) = resize.size(&original, config);
+    let format = match resize.format {
+        Some(format) => format,
+        None => negotiate(accept, config.derived_format.format_for(original.format()))?,
+    };
+    let encoding = config.encoding(format);
+    let variant = resize.variant(width, height, encoding);
+
+    if let Some(cached) = gallery.get_cached(name, &variant)? {
+        return Ok(cached);
+    }
+    let image = original.transform(width, height, resize.fit, encoding)?;
+    // Concurrent request may have already cached the same variant
+    if let Err(e) = gallery.put_cached(&original, &variant, &image) {
+        warn!("Failed to cache {} of {}: {}", variant, name, e);
+    }
+    Ok(image)
+}
+
+/// Returns `derived` image of image with `name`, generated again with `encoding`,
+/// when stored derived image has another format.
+/// Results are cached in gallery
+///
+/// # Errors
+/// If there is no image with given name
+/// If transformation failed
+///
+pub fn converted(gallery: &Gallery, name: &str, derived: Derived, encoding: Encoding) -> Result<Image, ApiError> {
+    let variant = match derived {
+        Derived::Preview => format!("preview_{}{}", encoding.format, encoding.quality),
+        Derived::Rendition(width) => format!("rendition{}_{}{}", width, encoding.format, encoding.quality),
+    };
+    if let Some(cached) = gallery.get_cached(name, &variant)? {
+        return Ok(cached);
+    }
+    let original = gallery.get(name)?;
+    let image = match derived {
+        Derived::Preview => original.generate_preview(encoding)?,
+        Derived::Rendition(width) => original.generate_rendition(width, encoding)?,
+    };
+    if let Err(e) = gallery.put_cached(&original, &variant, &image) {
+        warn!("Failed to cache {} of {}: {}", variant, name, e);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageName;
+
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+
+    fn query(w: Option<&str>, h: Option<&str>, fit: Option<&str>, background: Option<&str>) -> ResizeQuery {
+        ResizeQuery {
+            w: w.map(str::to_string),
+            h: h.map(str::to_string),
+            fit: fit.map(str::to_string),
+            background: background.map(str::to_string),
+            format: None,
+        }
+    }
+
+    /// 40x20 pixels
+    fn landscape() -> Image {
+        let data = include_bytes!("../../tests/data/landscape.png").to_vec();
+        Image::new(ImageName::new("landscape.png").unwrap(), data).unwrap()
+    }
+
+    #[test]
+    fn fit_is_parsed_with_background_of_padding() {
+        assert_eq!(Fit::Contain, Resize::parse(&query(None, None, None, None)).unwrap().fit);
+        assert_eq!(Fit::Contain, Resize::parse(&query(None, None, Some("contain"), None)).unwrap().fit);
+        assert_eq!(Fit::Cover, Resize::parse(&query(None, None, Some("cover"), None)).unwrap().fit);
+        assert_eq!(Fit::Fill, Resize::parse(&query(None, None, Some("fill"), None)).unwrap().fit);
+        assert_eq!(Fit::Pad(Rgb(255, 255, 255)), Resize::parse(&query(None, None, Some("pad"), None)).unwrap().fit);
+        assert_eq!(Fit::Pad(Rgb(0x12, 0x34, 0xAB)),
+                   Resize::parse(&query(None, None, Some("pad"), Some("#1234ab"))).unwrap().fit);
+    }
+
+    #[test]
+    fn dimensions_and_format_are_parsed() {
+        let resize = Resize::parse(&ResizeQuery {
+            format: Some("PNG".to_string()),
+            ..query(Some("64"), Some("32"), None, None)
+        }).unwrap();
+
+        assert_eq!(Resize { width: Some(64), height: Some(32), fit: Fit::Contain, format: Some(OutputFormat::Png) },
+                   resize);
+    }
+
+    #[test]
+    fn invalid_parameters_are_bad_requests() {
+        let invalid = [
+            query(Some("0"), None, None, None),
+            query(Some("-1"), None, None, None),
+            query(None, Some("tall"), None, None),
+            query(Some("99999999999"), None, None, None),
+            query(None, None, Some("stretch"), None),
+            query(None, None, Some("pad"), Some("fff")),
+            query(None, None, Some("pad"), Some("+12345")),
+            query(None, None, Some("pad"), Some("gggggg")),
+            ResizeQuery { format: Some("gif".to_string()), ..ResizeQuery::default() },
+        ];
+        for query in &invalid {
+            match Resize::parse(query) {
+                Err(e @ ApiError::InvalidParameter(_)) => assert_eq!(StatusCode::BAD_REQUEST, e.status_code()),
+                other => panic!("{:?} is parsed as {:?}", query, other),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_dimension_keeps_aspect_ratio() {
+        let config = Config::default();
+        let size = |w, h| Resize::parse(&query(w, h, None, None)).unwrap().size(&landscape(), &config);
+
+        assert_eq!((40, 20), size(None, None));
+        assert_eq!((10, 5), size(Some("10"), None));
+        assert_eq!((20, 10), size(None, Some("10")));
+        assert_eq!((10, 30), size(Some("10"), Some("30")));
+    }
+
+    #[test]
+    fn size_is_clamped_to_configured_limits() {
+        let config = Config { max_resize_width: 30, max_resize_height: 12, ..Config::default() };
+        let size = |w, h| Resize::parse(&query(w, h, None, None)).unwrap().size(&landscape(), &config);
+
+        assert_eq!((30, 12), size(Some("5000"), Some("5000")));
+        assert_eq!((30, 12), size(None, None));
+        assert_eq!((30, 12), size(Some("1000"), None));
+        assert_eq!((24, 12), size(None, Some("1000")));
+    }
+}
@@ -0,0 +1,118 @@
+//! Bodies of responses: per-image entries and errors
+use crate::server::ApiError;
+use crate::server::request_id::RequestId;
+
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Body of error response. The same fields describe failed images
+/// in ['ResponseMessage']
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorBody {
+    /// HTTP status code
+    pub code: u16,
+    /// Stable machine readable kind, like "unsupported_format" or "name_conflict"
+    pub kind: String,
+    pub message: String,
+    /// Values specific to kind, like limit which was exceeded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+    /// Describes `e`. Messages of internal errors are not revealed, but logged with `request_id`.
+    /// Errors without id are not logged, since their responses are rebuilt by ['RequestId::attach']
+    pub fn new(e: &ApiError, request_id: Option<&RequestId>) -> Self {
+        let status = e.status_code();
+        let message = if status == StatusCode::INTERNAL_SERVER_ERROR {
+            if let Some(RequestId(id)) = request_id {
+                error!("Request {} failed: {}", id, e);
+            }
+            "Internal server error".to_string()
+        } else {
+            format!("{}", e)
+        };
+        ErrorBody {
+            code: status.as_u16(),
+            kind: e.kind().to_string(),
+            message,
+            details: e.details(),
+            request_id: request_id.map(|id| id.0.clone()),
+        }
+    }
+
+    /// Describes error, which is not ['ApiError'], like invalid JSON of request
+    pub fn generic(status: StatusCode, message: &str, request_id: Option<&RequestId>) -> Self {
+        let (kind, message) = if status.is_client_error() {
+            ("invalid_request", message.to_string())
+        } else {
+            ("internal", "Internal server error".to_string())
+        };
+        ErrorBody {
+            code: status.as_u16(),
+            kind: kind.to_string(),
+            message,
+            details: None,
+            request_id: request_id.map(|id| id.0.clone()),
+        }
+    }
+}
+
+/// Result of processing of one image of request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResponseMessage {
+    pub code: u16,
+    pub message: String,
+    /// Name under which image is stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Digest of already stored identical content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// URLs of generated renditions, like "/images/name/renditions/64"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renditions: Vec<String>,
+    /// Kind of error, see ['ErrorBody']
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ResponseMessage {
+    pub fn new(code: u16, message: String) -> ResponseMessage {
+        ResponseMessage {
+            code,
+            message,
+            name: None,
+            duplicate_of: None,
+            renditions: vec![],
+            kind: None,
+            details: None,
+            request_id: None,
+        }
+    }
+
+    /// Entry of image failed in request with `request_id`
+    pub fn error(e: &ApiError, request_id: Option<&RequestId>) -> ResponseMessage {
+        let ErrorBody { code, kind, message, details, request_id } = ErrorBody::new(e, request_id);
+        ResponseMessage {
+            kind: Some(kind),
+            details,
+            request_id,
+            ..ResponseMessage::new(code, message)
+        }
+    }
+}
+
+impl From<ApiError> for ResponseMessage {
+    fn from(e: ApiError) -> Self {
+        ResponseMessage::error(&e, None)
+    }
+}
@@ -1,95 +1,438 @@
 //! Server configuration
 //! Supported routes and preferences
 use crate::server::{ApiJsonRequest, ApiUrlRequest, SupportedRequest, ApiError};
-use crate::image::Image;
+use crate::image::{Encoding, Image, OutputFormat};
 
-use actix_web::{web, guard, HttpResponse, Result, ResponseError};
+use actix_web::{web, guard, HttpRequest, HttpResponse, Result};
 
 use actix_multipart::Multipart;
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 
 use serde::{Deserialize, Serialize};
 
-use crate::server::store::store;
+use crate::server::{Gallery, Storage, Stored, Conflict, Derived};
+use crate::server::listing::{self, ListQuery};
+use crate::server::resize::{self, ResizeQuery};
+use crate::server::{Config, BlockingPool, FormatPolicy};
+use crate::server::negotiation::{self, negotiate};
+use crate::server::response::ResponseMessage;
+use crate::server::{Jobs, RequestId};
 
-#[derive(Serialize, Deserialize)]
-struct ResponseMessage {
-    code: u16,
-    message: String,
+use futures::future::{self, FutureExt};
+
+/// Query parameters of upload requests
+#[derive(Deserialize, Debug)]
+struct UploadQuery {
+    /// Policy for names of already stored images
+    #[serde(default)]
+    conflict: Conflict,
+    /// Process images in background and respond with job's id
+    #[serde(default, rename = "async")]
+    asynchronous: bool,
+    /// How results of images determine status of response
+    #[serde(default)]
+    batch: Batch,
+    /// Format of derived images instead of configured one
+    format: Option<String>,
 }
 
-impl ResponseMessage {
-    fn new(code: u16, message: String) -> ResponseMessage {
-        ResponseMessage { code, message }
-    }
+/// Query parameters of requests for derived images
+#[derive(Deserialize, Debug)]
+struct DerivedQuery {
+    /// Output format, negotiated by `Accept` header if missing
+    format: Option<String>,
 }
 
-impl From<ApiError> for ResponseMessage {
-    fn from(e: ApiError) -> Self {
-        use ApiError::*;
-        let code = match &e {
-            Base64Decoding | LocalhostUrl => 400,
-            _ => 500,
-        };
-        let message = match code {
-            500 => "Internal server error".to_string(),
-            _ => format!("{}", e).to_string()
-        };
+/// Status of response to upload request
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Batch {
+    /// 200 OK, results of images are only in their entries
+    #[default]
+    Lenient,
+    /// 207 Multi-Status with status of every image in its entry
+    Multi,
+    /// All or nothing: if any image fails, already stored images are removed
+    /// and response has status of the first failure
+    Strict,
+}
 
-        ResponseMessage::new(
-            code,
-            message,
-        )
-    }
+/// Response to asynchronous upload request
+#[derive(Serialize, Deserialize)]
+struct JobCreated {
+    id: String,
+    /// URL of job's status
+    status: String,
 }
 
 /// Post request method for [`SupportedRequest`] types
 /// Creates ['Image'] from path(as a name) and extracted data from request,
-/// Store it in database and return response with name
+/// Store it in shared ['Storage'] and return response with name
+/// Images are processed by ['BlockingPool']
+/// With `async=true` query parameter responds 202 Accepted with id of job,
+/// which status is available by ['job_status']. Too many unfinished jobs
+/// are rejected with 503 Service Unavailable
+/// With `batch=multi` responds 207 Multi-Status, with `batch=strict` stores
+/// either all images or none, see ['Batch']
+/// With `format` (jpeg|png|webp) query parameter derived images are encoded
+/// to it instead of configured ['FormatPolicy']
 ///
 /// # Errors
+/// If query parameters are inconsistent
+/// If request exceeds configured limits
 /// If extraction filed
-/// If database storing failed
+/// If storing failed
+/// If pool rejected every image of request
+/// If too many asynchronous uploads are unfinished
+/// If any image failed in strict mode
 ///
-async fn create<T: SupportedRequest>(request: T) -> Result<HttpResponse> {
-    let images = request.extract().await;
+async fn create<T: SupportedRequest + 'static>(request: T, query: web::Query<UploadQuery>, storage: Storage,
+                                               pool: web::Data<BlockingPool>, jobs: web::Data<Jobs>,
+                                               config: web::Data<Config>, request_id: RequestId) -> Result<HttpResponse> {
+    request.validate(&config)?;
+    let conflict = query.conflict;
+    let policy = negotiation::parse_format(&query.format)?
+        .map(FormatPolicy::Fixed)
+        .unwrap_or(config.derived_format);
+    if query.asynchronous && query.batch != Batch::Lenient {
+        return Err(ApiError::InvalidParameter("batch must be lenient for asynchronous upload".to_string()).into());
+    }
+    if query.batch == Batch::Strict && conflict == Conflict::Overwrite {
+        // Overwritten images cannot be restored
+        return Err(ApiError::InvalidParameter("conflict must not be overwrite in strict batch".to_string()).into());
+    }
+    if query.asynchronous {
+        // Rejected before payload is red
+        let id = jobs.create()?;
+        // Payload is not available after response is sent
+        let images = if T::STREAMED {
+            future::ready(request.extract(&config).await).boxed_local()
+        } else {
+            let config = config.clone();
+            async move { request.extract(&config).await }.boxed_local()
+        };
+        let job_id = id.clone();
+        actix_rt::spawn(async move {
+            let images = images.await;
+            jobs.start(&job_id, images.len());
+            for image in images {
+                let result = process(image, storage.clone(), &pool, conflict, &config, policy).await;
+                jobs.record(&job_id, result.unwrap_or_else(|e| ResponseMessage::error(&e, Some(&request_id))));
+            }
+            jobs.finish(&job_id);
+        });
+        return Ok(HttpResponse::Accepted()
+            .json(JobCreated { status: format!("/jobs/{}", id), id }));
+    }
+
+    let images = request.extract(&config).await;
+    if query.batch == Batch::Strict {
+        let response = store_all(images, storage, &pool, conflict, &config, policy).await?;
+        return Ok(HttpResponse::Ok()
+            .json(response));
+    }
     let mut response = vec![];
+    let mut rejected = None;
     for image in images {
         response.push(
-            match image_process(image) {
+            match process(image, storage.clone(), &pool, conflict, &config, policy).await {
                 Ok(response_message) => response_message,
-                Err(e) => ResponseMessage::from(e)
+                Err(e) => {
+                    if let ApiError::Overloaded(retry_after) = e {
+                        rejected = Some(retry_after);
+                    }
+                    ResponseMessage::error(&e, Some(&request_id))
+                }
             }
         );
     }
-    Ok(HttpResponse::Ok()
+    // Nothing is done, so the whole request can be retried
+    if let Some(retry_after) = rejected {
+        if response.iter().all(|message| message.code == StatusCode::SERVICE_UNAVAILABLE.as_u16()) {
+            return Err(ApiError::Overloaded(retry_after).into());
+        }
+    }
+    let status = match query.batch {
+        Batch::Multi => StatusCode::MULTI_STATUS,
+        _ => StatusCode::OK,
+    };
+    Ok(HttpResponse::build(status)
         .json(response))
 }
 
+/// Stores all images or none of them.
+/// Images are not processed if any of them failed to be extracted
+///
+/// # Errors
+/// The first error of images. Already stored images are removed
+/// outside of ['BlockingPool'], which may be the one that failed
+///
+async fn store_all(images: Vec<Result<Image, ApiError>>, storage: Storage, pool: &BlockingPool,
+                   conflict: Conflict, config: &Config,
+                   policy: FormatPolicy) -> Result<Vec<ResponseMessage>, ApiError> {
+    let images = images.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let mut response = vec![];
+    for image in images {
+        match process(Ok(image), storage.clone(), pool, conflict, config, policy).await {
+            Ok(response_message) => response.push(response_message),
+            Err(e) => {
+                let names = response.into_iter()
+                    .filter_map(|response_message: ResponseMessage| response_message.name)
+                    .collect::<Vec<_>>();
+                let gallery = storage.clone();
+                // Full queue of the pool must not keep stored images
+                let removed = web::block(move || {
+                    for name in names {
+                        gallery.delete(&name)?;
+                    }
+                    Ok::<_, ApiError>(())
+                }).await;
+                if let Err(removal) = removed {
+                    error!("Failed to roll back strict batch: {}", removal);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(response)
+}
 
-fn image_process(image: Result<Image, ApiError>) -> Result<ResponseMessage, ApiError> {
+/// Processes extracted image by ['BlockingPool'].
+/// Derived images are encoded to format chosen by `policy`
+async fn process(image: Result<Image, ApiError>, gallery: Storage, pool: &BlockingPool,
+                 conflict: Conflict, config: &Config, policy: FormatPolicy) -> Result<ResponseMessage, ApiError> {
     let image = image?;
-    let path = std::path::Path::new("./images");
-    store(&image, path)?;
-
-    let preview = image.generate_preview()?;
-    let path_preview = &path.join(std::path::Path::new("preview"));
-    store(&preview, path_preview)?;
-    Ok(
-        ResponseMessage::new(
-            StatusCode::OK.as_u16(),
-            format!("Image {} successfully uploaded", image.name()),
-        )
-    )
+    let encoding = config.encoding(policy.format_for(image.format()));
+    pool.run(move || image_process(image, &gallery, conflict, encoding)).await
+}
+
+/// Get request method for status of asynchronous upload
+///
+/// # Errors
+/// If there is no job with given id
+///
+async fn job_status(id: web::Path<String>, jobs: web::Data<Jobs>) -> Result<HttpResponse> {
+    let status = jobs.status(&id)?;
+    Ok(HttpResponse::Ok()
+        .json(status))
+}
+
+
+/// Stores image with its derived images.
+/// If derived images cannot be generated, stored image is removed
+fn image_process(mut image: Image, gallery: &Gallery, conflict: Conflict,
+                 encoding: Encoding) -> Result<ResponseMessage, ApiError> {
+    let stored = gallery.put(&mut image, conflict)?;
+
+    let renditions = match derive_all(&image, gallery, encoding) {
+        Ok(renditions) => renditions,
+        Err(e) => {
+            if let Err(removal) = gallery.delete(image.name()) {
+                error!("Failed to remove {} without derived images: {}", image.name(), removal);
+            }
+            return Err(e);
+        }
+    };
+
+    let mut response = ResponseMessage::new(
+        StatusCode::OK.as_u16(),
+        format!("Image {} successfully uploaded", image.name()),
+    );
+    if let Stored::Duplicate(digest) = stored {
+        response.message = format!("Image {} successfully uploaded as duplicate of stored content", image.name());
+        response.duplicate_of = Some(digest);
+    }
+    response.renditions = renditions.iter()
+        .map(|width| format!("/images/{}/renditions/{}", image.name(), width))
+        .collect();
+    response.name = Some(image.name().to_string());
+    Ok(response)
+}
+
+/// Generates missing derived images of stored `image`, encoded by `encoding`.
+/// Returns widths of renditions
+fn derive_all(image: &Image, gallery: &Gallery, encoding: Encoding) -> Result<Vec<u32>, ApiError> {
+    // Identical content may already have derived images
+    let mut renditions = vec![];
+    for derived in gallery.derived() {
+        if let Derived::Rendition(width) = derived {
+            renditions.push(width);
+        }
+        if gallery.has_derived(image, derived)? {
+            continue;
+        }
+        let derived_image = match derived {
+            Derived::Preview => image.generate_preview(encoding)?,
+            Derived::Rendition(width) => image.generate_rendition(width, encoding)?,
+        };
+        gallery.put_derived(image, derived, &derived_image)?;
+    }
+    Ok(renditions)
 }
 
 
+/// Get request method for page of stored images
+/// Query parameters: `limit`, `cursor`, `sort` (name|time), `order` (asc|desc)
+///
+/// # Errors
+/// If cursor is malformed
+/// If storage cannot be red
+/// If listing is rejected by ['BlockingPool']
+///
+async fn index(query: web::Query<ListQuery>, storage: Storage,
+               pool: web::Data<BlockingPool>) -> Result<HttpResponse> {
+    let query = query.into_inner();
+    let page = pool.run(move || listing::list(&storage, query)).await?;
+    Ok(HttpResponse::Ok()
+        .json(page))
+}
+
+/// Get request method for stored original image.
+/// Responds with image's binary data and its MIME type as content type
+/// Query parameters `w`, `h`, `fit` (cover|contain|fill|pad), `background` (rrggbb)
+/// and `format` (jpeg|png|webp)
+/// request image resized on demand. Without `format` it is negotiated by `Accept` header
+///
+/// # Errors
+/// If there is no image with given name
+/// If query parameters are invalid
+/// If none of output formats is acceptable
+/// If loading or resizing is rejected by ['BlockingPool']
+///
+async fn download(name: web::Path<String>, query: web::Query<ResizeQuery>, request: HttpRequest, storage: Storage,
+                  config: web::Data<Config>, pool: web::Data<BlockingPool>) -> Result<HttpResponse> {
+    let query = query.into_inner();
+    let name = name.into_inner();
+    let gallery = storage.clone();
+    if query.is_empty() {
+        let image = pool.run(move || gallery.get(&name)).await?;
+        return Ok(HttpResponse::Ok()
+            .content_type(image.mime_type())
+            .body(image.data().clone()));
+    }
+    let negotiated = query.format.is_none();
+    let accept = accept(&request);
+    let config = config.clone();
+    let image = pool.run(move || resize::resized(&gallery, &name, &query, accept.as_deref(), &config)).await?;
+    Ok(image_response(&image, negotiated))
+}
+
+/// Get request method for preview of stored image.
+/// Query parameter `format` (jpeg|png|webp) or `Accept` header choose its format
+///
+/// # Errors
+/// If there is no preview for image with given name
+/// If format parameter is invalid or none of formats is acceptable
+/// If loading or conversion is rejected by ['BlockingPool']
+///
+async fn download_preview(name: web::Path<String>, query: web::Query<DerivedQuery>, request: HttpRequest,
+                          storage: Storage, config: web::Data<Config>,
+                          pool: web::Data<BlockingPool>) -> Result<HttpResponse> {
+    download_derived(name.into_inner(), Derived::Preview, &query, &request, storage, &config, &pool).await
+}
+
+/// Get request method for rendition of stored image with given width.
+/// Query parameter `format` (jpeg|png|webp) or `Accept` header choose its format
+///
+/// # Errors
+/// If there is no such rendition for image with given name
+/// If format parameter is invalid or none of formats is acceptable
+/// If loading or conversion is rejected by ['BlockingPool']
+///
+async fn download_rendition(path: web::Path<(String, u32)>, query: web::Query<DerivedQuery>, request: HttpRequest,
+                            storage: Storage, config: web::Data<Config>,
+                            pool: web::Data<BlockingPool>) -> Result<HttpResponse> {
+    let (name, width) = path.into_inner();
+    download_derived(name, Derived::Rendition(width), &query, &request, storage, &config, &pool).await
+}
+
+/// Responds with stored derived image if its format is requested or acceptable,
+/// otherwise derived image is generated in chosen format
+async fn download_derived(name: String, derived: Derived, query: &DerivedQuery, request: &HttpRequest,
+                          storage: Storage, config: &Config, pool: &BlockingPool) -> Result<HttpResponse> {
+    let requested = negotiation::parse_format(&query.format)?;
+    let (gallery, original) = (storage.clone(), name.clone());
+    let image = pool.run(move || gallery.get_derived(&original, derived)).await?;
+    let stored = image.format().output_format();
+    let format = match (requested, stored) {
+        (Some(format), _) => format,
+        (None, Some(stored)) => negotiate(accept(request).as_deref(), stored)?,
+        (None, None) => negotiate(accept(request).as_deref(), OutputFormat::Jpeg)?,
+    };
+    if Some(format) == stored {
+        return Ok(image_response(&image, requested.is_none()));
+    }
+    let encoding = config.encoding(format);
+    let gallery = storage.clone();
+    let image = pool.run(move || resize::converted(&gallery, &name, derived, encoding)).await?;
+    Ok(image_response(&image, requested.is_none()))
+}
+
+/// Value of `Accept` header of request
+fn accept(request: &HttpRequest) -> Option<String> {
+    request.headers().get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Responds with image's binary data and its MIME type as content type.
+/// Format `negotiated` by `Accept` header is reported in `Vary` header for caches
+fn image_response(image: &Image, negotiated: bool) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if negotiated {
+        response.header(header::VARY, "Accept");
+    }
+    response
+        .content_type(image.mime_type())
+        .body(image.data().clone())
+}
+
+/// Get request method for metadata of stored image, see ['ImageMetadata']
+///
+/// # Errors
+/// If there is no image with given name
+/// If metadata cannot be red
+/// If reading is rejected by ['BlockingPool']
+///
+async fn download_metadata(name: web::Path<String>, storage: Storage,
+                           pool: web::Data<BlockingPool>) -> Result<HttpResponse> {
+    let name = name.into_inner();
+    let metadata = pool.run(move || storage.metadata(&name)).await?;
+    Ok(HttpResponse::Ok()
+        .json(metadata))
+}
+
+/// Delete request method for stored image.
+/// Removes original image together with its preview
+///
+/// # Errors
+/// If there is no image with given name
+/// If removal failed
+/// If removal is rejected by ['BlockingPool']
+///
+async fn remove(name: web::Path<String>, storage: Storage, pool: web::Data<BlockingPool>) -> Result<HttpResponse> {
+    let name = name.into_inner();
+    let removed = name.clone();
+    pool.run(move || storage.delete(&removed)).await?;
+    Ok(HttpResponse::Ok()
+        .json(ResponseMessage::new(
+            StatusCode::OK.as_u16(),
+            format!("Image {} successfully deleted", name),
+        )))
+}
+
 /// Configure routes
 /// Set guards for parsing "content-type" fields of request's headers
 /// and substitutes corresponding generic type for ['create()']
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/jobs/{id}")
+        .route(web::get().to(job_status))
+    );
     cfg.service(
         web::scope("/images/")
+            .service(web::resource("")
+                .route(web::get().to(index))
+            )
             .service(web::resource("from_url")
                 .route(
                     web::post()
@@ -108,6 +451,19 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
                         .guard(MultipartTypeGuard())
                         .to(create::<Multipart>))
             )
+            .service(web::resource("{name}")
+                .route(web::get().to(download))
+                .route(web::delete().to(remove))
+            )
+            .service(web::resource("{name}/metadata")
+                .route(web::get().to(download_metadata))
+            )
+            .service(web::resource("{name}/preview")
+                .route(web::get().to(download_preview))
+            )
+            .service(web::resource("{name}/renditions/{width}")
+                .route(web::get().to(download_rendition))
+            )
     );
 }
 
@@ -1,18 +1,181 @@
-use crate::image::Image;
-use std::io::Write;
+//! Storage backends of binary objects
+//! Images are laid out in backends by ['Gallery']
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::SystemTime;
 
-pub fn store(image: &Image, dir: &std::path::Path) -> Result<(), std::io::Error> {
-    if !dir.exists() {
-        std::fs::create_dir(dir)?;
+/// Key-value storage backend of binary objects.
+/// Keys are relative '/'-separated paths like "preview/preview_name.jpeg",
+/// so the part before the last '/' is a directory of the object
+///
+pub trait ImageStore: Send + Sync {
+    /// Stores `data` under `key`
+    ///
+    /// # Errors
+    /// If object with such key already exists (`io::ErrorKind::AlreadyExists`)
+    /// If backend fails to write data
+    ///
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Returns data stored under `key`
+    ///
+    /// # Errors
+    /// If there is no such object (`io::ErrorKind::NotFound`)
+    ///
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Removes object stored under `key`
+    ///
+    /// # Errors
+    /// If there is no such object (`io::ErrorKind::NotFound`)
+    ///
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Returns names (the part after the last '/') of objects in `dir`.
+    /// Objects of nested directories are not included
+    fn list(&self, dir: &str) -> io::Result<Vec<String>>;
+
+    fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Returns time when object stored under `key` was created
+    ///
+    /// # Errors
+    /// If there is no such object (`io::ErrorKind::NotFound`)
+    ///
+    fn created(&self, key: &str) -> io::Result<SystemTime>;
+}
+
+/// Default backend. Stores objects as files in `root` directory,
+/// directories of keys become sub-directories of `root`
+///
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        FsStore { root: root.into() }
     }
 
-    let file_path = dir.join(image.name())
-        .with_extension(image.extension());
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl ImageStore for FsStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let file_path = self.path(key);
+        if let Some(dir) = file_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
 
-    std::fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&file_path)?
-        .write(image.data())?;
-    Ok(())
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file_path)?
+            .write_all(data)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.path(key))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        std::fs::remove_file(self.path(key))
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let dir = self.path(dir);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.path(key).is_file())
+    }
+
+    fn created(&self, key: &str) -> io::Result<SystemTime> {
+        let metadata = std::fs::metadata(self.path(key))?;
+        // Not every filesystem keeps creation time
+        metadata.created().or_else(|_| metadata.modified())
+    }
+}
+
+/// Backend keeping objects in memory. Intended for tests
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: RwLock<BTreeMap<String, Object>>,
+}
+
+struct Object {
+    data: Vec<u8>,
+    created: SystemTime,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+fn poisoned<T>(_: T) -> io::Error {
+    io::Error::other("Storage lock poisoned")
+}
+
+impl ImageStore for MemoryStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let mut objects = self.objects.write().map_err(poisoned)?;
+        if objects.contains_key(key) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, key.to_string()));
+        }
+        objects.insert(key.to_string(), Object { data: data.to_vec(), created: SystemTime::now() });
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.objects.read().map_err(poisoned)?
+            .get(key)
+            .map(|object| object.data.clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, key.to_string()))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.objects.write().map_err(poisoned)?
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, key.to_string()))
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+        let names = self.objects.read().map_err(poisoned)?
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix as &str))
+            .filter(|name| !name.contains('/'))
+            .map(|name| name.to_string())
+            .collect();
+        Ok(names)
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.objects.read().map_err(poisoned)?.contains_key(key))
+    }
+
+    fn created(&self, key: &str) -> io::Result<SystemTime> {
+        self.objects.read().map_err(poisoned)?
+            .get(key)
+            .map(|object| object.created)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, key.to_string()))
+    }
 }
@@ -0,0 +1,41 @@
+//! Helpers shared by integration tests
+use image_api::server::{ImageStore, MemoryStore};
+
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Memory store shared with test, which fails removal of `failing` key
+pub struct SharedStore {
+    pub store: Arc<MemoryStore>,
+    pub failing: Option<String>,
+}
+
+impl ImageStore for SharedStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.store.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.store.get(key)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        if self.failing.as_deref() == Some(key) {
+            return Err(io::Error::other("Removal failed"));
+        }
+        self.store.delete(key)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        self.store.list(dir)
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        self.store.exists(key)
+    }
+
+    fn created(&self, key: &str) -> io::Result<SystemTime> {
+        self.store.created(key)
+    }
+}
@@ -0,0 +1,198 @@
+//! Layout of images in storage, checked on ['MemoryStore']
+mod common;
+
+use common::SharedStore;
+use image_api::image::{Encoding, Image, OutputFormat, Source};
+use image_api::server::{ApiError, Conflict, Derived, Gallery, ImageStore, Layout, MemoryStore, Stored};
+
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+const LANDSCAPE_PNG: &[u8] = include_bytes!("data/landscape.png");
+const ROTATED_JPEG: &[u8] = include_bytes!("data/rotated.jpg");
+
+fn gallery(layout: Layout, failing: Option<&str>) -> (Gallery, Arc<MemoryStore>) {
+    let store = Arc::new(MemoryStore::new());
+    let shared = SharedStore { store: store.clone(), failing: failing.map(str::to_string) };
+    (Gallery::new(Box::new(shared), layout, vec![]), store)
+}
+
+fn image(name: &str) -> Image {
+    Image::create(name.to_string(), LANDSCAPE_PNG.to_vec(), Source::Multipart).unwrap()
+}
+
+fn preview(image: &Image) -> Image {
+    image.generate_preview(Encoding::new(OutputFormat::Jpeg)).unwrap()
+}
+
+#[test]
+fn identical_content_is_stored_once() {
+    let (gallery, store) = gallery(Layout::ContentAddressed, None);
+    let (mut first, mut second) = (image("first.png"), image("second.png"));
+
+    assert_eq!(Stored::New, gallery.put(&mut first, Conflict::Reject).unwrap());
+    assert_eq!(Stored::Duplicate(first.digest()), gallery.put(&mut second, Conflict::Reject).unwrap());
+
+    assert_eq!(1, store.list("blobs").unwrap().len());
+    assert_eq!(2, store.list("refs").unwrap().len());
+    assert_eq!(first.data(), gallery.get("second.png").unwrap().data());
+}
+
+#[test]
+fn identical_content_has_one_preview() {
+    let (gallery, store) = gallery(Layout::ContentAddressed, None);
+    let (mut first, mut second) = (image("first.png"), image("second.png"));
+    gallery.put(&mut first, Conflict::Reject).unwrap();
+    gallery.put_derived(&first, Derived::Preview, &preview(&first)).unwrap();
+
+    gallery.put(&mut second, Conflict::Reject).unwrap();
+
+    assert!(gallery.has_derived(&second, Derived::Preview).unwrap());
+    assert_eq!(1, store.list("preview").unwrap().len());
+    assert!(gallery.get_derived("second.png", Derived::Preview).is_ok());
+}
+
+#[test]
+fn shared_content_is_kept_until_last_name_is_removed() {
+    let (gallery, store) = gallery(Layout::ContentAddressed, None);
+    let (mut first, mut second) = (image("first.png"), image("second.png"));
+    gallery.put(&mut first, Conflict::Reject).unwrap();
+    gallery.put_derived(&first, Derived::Preview, &preview(&first)).unwrap();
+    gallery.put(&mut second, Conflict::Reject).unwrap();
+
+    gallery.delete("first.png").unwrap();
+    assert_eq!(1, store.list("blobs").unwrap().len());
+    assert!(gallery.get_derived("second.png", Derived::Preview).is_ok());
+
+    gallery.delete("second.png").unwrap();
+    assert!(store.list("blobs").unwrap().is_empty());
+    assert!(store.list("preview").unwrap().is_empty());
+}
+
+#[test]
+fn failed_removal_restores_removed_objects() {
+    let (gallery, store) = gallery(Layout::ByName, Some("preview/preview_cat.jpeg"));
+    let mut cat = image("cat.png");
+    gallery.put(&mut cat, Conflict::Reject).unwrap();
+    gallery.put_derived(&cat, Derived::Preview, &preview(&cat)).unwrap();
+
+    assert!(gallery.delete("cat.png").is_err());
+
+    assert!(store.exists("cat.png").unwrap());
+    assert!(store.exists("preview/preview_cat.jpeg").unwrap());
+    assert_eq!(cat.data(), gallery.get("cat.png").unwrap().data());
+}
+
+#[test]
+fn removal_includes_derived_images() {
+    let (gallery, store) = gallery(Layout::ByName, None);
+    let mut cat = image("cat.png");
+    gallery.put(&mut cat, Conflict::Reject).unwrap();
+    gallery.put_derived(&cat, Derived::Preview, &preview(&cat)).unwrap();
+
+    gallery.delete("cat.png").unwrap();
+
+    assert!(!gallery.exists("cat.png").unwrap());
+    assert!(store.list("preview").unwrap().is_empty());
+}
+
+#[test]
+fn conflicting_name_is_rejected() {
+    let (gallery, _) = gallery(Layout::ByName, None);
+    gallery.put(&mut image("cat.png"), Conflict::Reject).unwrap();
+
+    let rejected = gallery.put(&mut image("cat.png"), Conflict::Reject);
+
+    assert!(matches!(rejected, Err(ApiError::NameConflict(name)) if name == "cat.png"));
+}
+
+#[test]
+fn conflicting_name_is_overwritten_with_derived_images() {
+    let (gallery, store) = gallery(Layout::ByName, None);
+    let mut cat = image("cat.png");
+    gallery.put(&mut cat, Conflict::Reject).unwrap();
+    gallery.put_derived(&cat, Derived::Preview, &preview(&cat)).unwrap();
+    let mut replacement = Image::create("cat.jpg".to_string(), ROTATED_JPEG.to_vec(), Source::Multipart).unwrap();
+
+    gallery.put(&mut replacement, Conflict::Overwrite).unwrap();
+
+    assert_eq!("cat.jpg", replacement.name());
+    assert_eq!(ROTATED_JPEG, &gallery.get("cat.jpg").unwrap().data()[..]);
+    assert!(!store.exists("cat.png").unwrap());
+    assert!(!gallery.has_derived(&replacement, Derived::Preview).unwrap());
+}
+
+#[test]
+fn conflicting_name_is_renamed() {
+    let (gallery, _) = gallery(Layout::ContentAddressed, None);
+    gallery.put(&mut image("cat.png"), Conflict::Reject).unwrap();
+    let mut second = image("cat.png");
+    let mut third = image("cat.png");
+
+    gallery.put(&mut second, Conflict::Rename).unwrap();
+    gallery.put(&mut third, Conflict::Rename).unwrap();
+
+    assert_eq!("cat_1.png", second.name());
+    assert_eq!("cat_2.png", third.name());
+    assert!(gallery.exists("cat.png").unwrap());
+    assert!(gallery.exists("cat_1.png").unwrap());
+}
+
+#[test]
+fn metadata_is_found_by_listed_name() {
+    let (gallery, store) = gallery(Layout::ByName, None);
+    gallery.put(&mut image("cat.png"), Conflict::Reject).unwrap();
+    let (name, _) = gallery.names().unwrap().pop().unwrap();
+
+    let metadata = gallery.metadata(&name).unwrap();
+
+    assert_eq!("cat", name);
+    assert!(metadata.uploaded.is_some());
+    assert_eq!(Some(Source::Multipart), metadata.source);
+
+    gallery.delete(&name).unwrap();
+    assert!(store.list("metadata").unwrap().is_empty());
+}
+
+/// Store, which does not see blobs stored by concurrent uploads until it writes them
+struct RacingStore(Arc<MemoryStore>);
+
+impl ImageStore for RacingStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.0.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.0.get(key)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.0.delete(key)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        self.0.list(dir)
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(!key.starts_with("blobs/") && self.0.exists(key)?)
+    }
+
+    fn created(&self, key: &str) -> io::Result<SystemTime> {
+        self.0.created(key)
+    }
+}
+
+#[test]
+fn concurrently_stored_identical_content_is_duplicate() {
+    let store = Arc::new(MemoryStore::new());
+    let gallery = Gallery::new(Box::new(RacingStore(store.clone())), Layout::ContentAddressed, vec![]);
+    let (mut first, mut second) = (image("first.png"), image("second.png"));
+
+    assert_eq!(Stored::New, gallery.put(&mut first, Conflict::Reject).unwrap());
+    assert_eq!(Stored::Duplicate(first.digest()), gallery.put(&mut second, Conflict::Reject).unwrap());
+
+    assert_eq!(1, store.list("blobs").unwrap().len());
+    assert_eq!(first.data(), gallery.get("second.png").unwrap().data());
+}
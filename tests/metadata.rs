@@ -0,0 +1,119 @@
+//! Metadata red from headers of images, which are made up here, since only headers are parsed
+use image_api::image::{ColourType, ImageFormat, ImageMetadata};
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+/// RIFF container with the first chunk of webp
+fn webp(chunk: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = b"RIFF".to_vec();
+    data.extend_from_slice(&(12 + payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(b"WEBP");
+    data.extend_from_slice(chunk);
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Tiff with a single directory of `(tag, type, count, value)` entries, followed by `values`
+fn tiff(big_endian: bool, entries: &[(u16, u16, u32, u32)], values: &[u8]) -> Vec<u8> {
+    let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+    let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+    let mut data = if big_endian { b"MM\0*".to_vec() } else { b"II*\0".to_vec() };
+    data.extend_from_slice(&u32_bytes(8));
+    data.extend_from_slice(&u16_bytes(entries.len() as u16));
+    for &(tag, kind, count, value) in entries {
+        data.extend_from_slice(&u16_bytes(tag));
+        data.extend_from_slice(&u16_bytes(kind));
+        data.extend_from_slice(&u32_bytes(count));
+        // Short values are aligned to the start of the field
+        match kind {
+            SHORT if count <= 2 => data.extend_from_slice(&[&u16_bytes(value as u16)[..], &[0, 0]].concat()),
+            _ => data.extend_from_slice(&u32_bytes(value)),
+        }
+    }
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(values);
+    data
+}
+
+#[test]
+fn lossy_webp_header() {
+    let mut payload = vec![0x10, 0x02, 0x00, 0x9D, 0x01, 0x2A];
+    // The upper two bits of dimensions are scale
+    payload.extend_from_slice(&(40u16 | 0x4000).to_le_bytes());
+    payload.extend_from_slice(&20u16.to_le_bytes());
+
+    let metadata = ImageMetadata::read(&webp(b"VP8 ", &payload)).unwrap();
+
+    assert_eq!(ImageFormat::Webp, metadata.format);
+    assert_eq!((40, 20), (metadata.width, metadata.height));
+    assert_eq!(ColourType::Rgb, metadata.colour_type);
+    assert_eq!(8, metadata.bit_depth);
+}
+
+#[test]
+fn lossless_webp_header() {
+    let bits: u32 = (40 - 1) | (20 - 1) << 14 | 1 << 28;
+    let payload = [&[0x2F][..], &bits.to_le_bytes()].concat();
+
+    let metadata = ImageMetadata::read(&webp(b"VP8L", &payload)).unwrap();
+
+    assert_eq!((40, 20), (metadata.width, metadata.height));
+    assert_eq!(ColourType::Rgba, metadata.colour_type);
+}
+
+#[test]
+fn extended_webp_header() {
+    let mut payload = vec![0x00; 4];
+    payload.extend_from_slice(&(40u32 - 1).to_le_bytes()[..3]);
+    payload.extend_from_slice(&(20u32 - 1).to_le_bytes()[..3]);
+
+    let metadata = ImageMetadata::read(&webp(b"VP8X", &payload)).unwrap();
+    assert_eq!((40, 20), (metadata.width, metadata.height));
+    assert_eq!(ColourType::Rgb, metadata.colour_type);
+
+    payload[0] = 0x10;
+    let metadata = ImageMetadata::read(&webp(b"VP8X", &payload)).unwrap();
+    assert_eq!(ColourType::Rgba, metadata.colour_type);
+}
+
+#[test]
+fn malformed_webp_header_is_rejected() {
+    let payload = [0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 40, 0, 20, 0];
+    assert!(ImageMetadata::read(&webp(b"VP8 ", &payload)).is_err());
+    assert!(ImageMetadata::read(&webp(b"VP8L", &[0x2F, 0x00])).is_err());
+    assert!(ImageMetadata::read(&webp(b"ALPH", &[0; 10])).is_err());
+}
+
+#[test]
+fn little_endian_tiff_header() {
+    let entries = [(256, SHORT, 1, 40), (257, SHORT, 1, 20), (258, SHORT, 1, 8), (262, SHORT, 1, 1)];
+
+    let metadata = ImageMetadata::read(&tiff(false, &entries, &[])).unwrap();
+
+    assert_eq!(ImageFormat::Tiff, metadata.format);
+    assert_eq!((40, 20), (metadata.width, metadata.height));
+    assert_eq!(ColourType::Grayscale, metadata.colour_type);
+    assert_eq!(8, metadata.bit_depth);
+}
+
+#[test]
+fn big_endian_tiff_header() {
+    // Bits of three samples do not fit into the entry and are stored after directory
+    let values_offset = 8 + 2 + 4 * 12 + 4;
+    let entries = [(256, LONG, 1, 40), (257, LONG, 1, 20), (258, SHORT, 3, values_offset), (277, SHORT, 1, 3)];
+    let values = [0, 8, 0, 8, 0, 8];
+
+    let metadata = ImageMetadata::read(&tiff(true, &entries, &values)).unwrap();
+
+    assert_eq!((40, 20), (metadata.width, metadata.height));
+    assert_eq!(ColourType::Rgb, metadata.colour_type);
+    assert_eq!(8, metadata.bit_depth);
+}
+
+#[test]
+fn tiff_without_dimensions_is_rejected() {
+    assert!(ImageMetadata::read(&tiff(false, &[(256, SHORT, 1, 40)], &[])).is_err());
+    assert!(ImageMetadata::read(b"II*\0\xFF\0\0\0").is_err());
+}
@@ -0,0 +1,69 @@
+//! Validation of image names, which become file names in storage
+use image_api::image::{Image, ImageError, ImageName, Source, MAX_NAME_LENGTH};
+
+const LANDSCAPE_PNG: &[u8] = include_bytes!("data/landscape.png");
+
+fn rejected(name: &str) -> bool {
+    matches!(ImageName::new(name), Err(ImageError::InvalidName(_)))
+}
+
+#[test]
+fn path_traversal_is_rejected() {
+    assert!(rejected("../../etc/cron.d/x"));
+    assert!(rejected(".."));
+    assert!(rejected("..\\..\\windows\\x"));
+    assert!(rejected("dir/cat.png"));
+}
+
+#[test]
+fn absolute_paths_are_rejected() {
+    assert!(rejected("/etc/passwd"));
+    assert!(rejected("C:\\images\\cat.png"));
+    assert!(rejected("\\\\server\\share\\cat.png"));
+}
+
+#[test]
+fn control_characters_are_rejected() {
+    assert!(rejected("cat\0.png"));
+    assert!(rejected("cat\n.png"));
+    assert!(rejected("cat\u{1b}[31m.png"));
+}
+
+#[test]
+fn reserved_and_hidden_names_are_rejected() {
+    assert!(rejected("CON"));
+    assert!(rejected("con.png"));
+    assert!(rejected("LPT1.jpg"));
+    assert!(rejected(".htaccess"));
+    assert!(rejected("cat."));
+}
+
+#[test]
+fn empty_and_long_names_are_rejected() {
+    assert!(rejected(""));
+    assert!(rejected("   "));
+    assert!(rejected(&"a".repeat(MAX_NAME_LENGTH + 1)));
+    assert!(ImageName::new(&"a".repeat(MAX_NAME_LENGTH)).is_ok());
+}
+
+#[test]
+fn names_are_normalised() {
+    let composed = ImageName::new("caf\u{e9}.png").unwrap();
+    let decomposed = ImageName::new("  cafe\u{301}.png ").unwrap();
+
+    assert_eq!(composed, decomposed);
+    assert_eq!("caf\u{e9}.png", decomposed.as_str());
+    assert!(ImageName::new("console.png").is_ok());
+}
+
+#[test]
+fn deserialized_names_are_validated() {
+    assert!(serde_json::from_str::<ImageName>("\"../x\"").is_err());
+    assert_eq!(ImageName::new("cat.png").unwrap(), serde_json::from_str("\"cat.png\"").unwrap());
+
+    let image = Image::create("cat.png".to_string(), LANDSCAPE_PNG.to_vec(), Source::Multipart).unwrap();
+    let mut json = serde_json::to_value(&image).unwrap();
+    json["name"] = "../../etc/cron.d/x".into();
+
+    assert!(serde_json::from_value::<Image>(json).is_err());
+}
@@ -0,0 +1,336 @@
+//! Requests to routes of the server, stored on ['MemoryStore']
+mod common;
+
+use common::SharedStore;
+use image_api::image::{Image, Source};
+use image_api::server::{
+    init_routes, BlockingPool, Config, Conflict, Gallery, ImageStore, Jobs, Layout, MemoryStore, RequestId, Storage,
+};
+
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use futures::future::FutureExt;
+use serde_json::{json, Value};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+const LANDSCAPE_PNG: &[u8] = include_bytes!("data/landscape.png");
+const BOUNDARY: &str = "image-api-boundary";
+
+/// Gallery of images stored by name, with its store
+fn storage() -> (Storage, Arc<MemoryStore>) {
+    let store = Arc::new(MemoryStore::new());
+    let shared = SharedStore { store: store.clone(), failing: None };
+    (web::Data::new(Gallery::new(Box::new(shared), Layout::ByName, vec![])), store)
+}
+
+/// Stores image with `name` directly in gallery
+fn stored(storage: &Storage, name: &str) {
+    let mut image = Image::create(name.to_string(), LANDSCAPE_PNG.to_vec(), Source::Multipart).unwrap();
+    storage.put(&mut image, Conflict::Reject).unwrap();
+}
+
+/// State of server shared between requests, configured like in ['image_api::run']
+struct Server {
+    storage: Storage,
+    config: web::Data<Config>,
+    pool: web::Data<BlockingPool>,
+    jobs: web::Data<Jobs>,
+}
+
+impl Server {
+    fn new(storage: &Storage, config: Config) -> Self {
+        Server {
+            storage: storage.clone(),
+            pool: web::Data::new(BlockingPool::new(2, 16, config.retry_after)),
+            jobs: web::Data::new(Jobs::new(Duration::from_secs(config.job_ttl), config.max_pending_jobs,
+                                           config.retry_after)),
+            config: web::Data::new(config),
+        }
+    }
+
+    async fn call(&self, request: test::TestRequest) -> ServiceResponse {
+        let mut app = test::init_service(App::new()
+            .app_data(self.storage.clone())
+            .app_data(self.config.clone())
+            .app_data(self.pool.clone())
+            .app_data(self.jobs.clone())
+            .wrap_fn(|request, service| {
+                let request_id = RequestId::assign(&request);
+                service.call(request)
+                    .map(move |response| response.map(|response| request_id.attach(response)))
+            })
+            .configure(init_routes)
+        ).await;
+        test::call_service(&mut app, request.to_request()).await
+    }
+}
+
+/// Sends single `request` to server with `storage` and `config`
+async fn call(storage: &Storage, config: Config, request: test::TestRequest) -> ServiceResponse {
+    Server::new(storage, config).call(request).await
+}
+
+/// Upload of base64 encoded landscape under every name of `names`
+fn json_upload(query: &str, names: &[&str]) -> test::TestRequest {
+    let images = names.iter()
+        .map(|name| json!({ "name": name, "data": base64::encode(LANDSCAPE_PNG) }))
+        .collect::<Vec<_>>();
+    test::TestRequest::post()
+        .uri(&format!("/images/from_json{}", query))
+        .set_json(&images)
+}
+
+/// Multipart upload of landscape for every filename of `filenames`, where missing one is omitted
+fn multipart_upload(filenames: &[Option<&str>]) -> test::TestRequest {
+    let mut payload = vec![];
+    for filename in filenames {
+        let disposition = match filename {
+            Some(filename) => format!("form-data; name=\"file\"; filename=\"{}\"", filename),
+            None => "form-data; name=\"file\"".to_string(),
+        };
+        payload.extend_from_slice(format!("--{}\r\nContent-Disposition: {}\r\nContent-Type: image/png\r\n\r\n",
+                                          BOUNDARY, disposition).as_bytes());
+        payload.extend_from_slice(LANDSCAPE_PNG);
+        payload.extend_from_slice(b"\r\n");
+    }
+    payload.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    test::TestRequest::post()
+        .uri("/images/from_multipart")
+        .header("content-type", format!("multipart/form-data; boundary={}", BOUNDARY))
+        .set_payload(payload)
+}
+
+async fn body(response: ServiceResponse) -> Value {
+    serde_json::from_slice(&test::read_body(response).await).unwrap()
+}
+
+#[actix_rt::test]
+async fn multi_status_reports_status_of_every_image() {
+    let (storage, _) = storage();
+    stored(&storage, "taken.png");
+
+    let response = call(&storage, Config::default(), json_upload("?batch=multi", &["new.png", "taken.png"])).await;
+
+    assert_eq!(StatusCode::MULTI_STATUS, response.status());
+    let body = body(response).await;
+    assert_eq!(200, body[0]["code"]);
+    assert_eq!("new.png", body[0]["name"]);
+    assert_eq!(409, body[1]["code"]);
+    assert_eq!("name_conflict", body[1]["kind"]);
+    assert!(body[1]["request_id"].is_string());
+}
+
+#[actix_rt::test]
+async fn strict_batch_removes_stored_images_on_failure() {
+    let (storage, _) = storage();
+    stored(&storage, "taken.png");
+
+    let response = call(&storage, Config::default(),
+                        json_upload("?batch=strict", &["first.png", "second.png", "taken.png"])).await;
+
+    assert_eq!(StatusCode::CONFLICT, response.status());
+    assert_eq!("name_conflict", body(response).await["kind"]);
+    assert!(!storage.exists("first.png").unwrap());
+    assert!(!storage.exists("second.png").unwrap());
+    assert!(storage.exists("taken.png").unwrap());
+}
+
+#[actix_rt::test]
+async fn strict_batch_stores_all_images() {
+    let (storage, _) = storage();
+
+    let response = call(&storage, Config::default(), json_upload("?batch=strict", &["first.png", "second.png"])).await;
+
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(2, body(response).await.as_array().unwrap().len());
+    assert!(storage.exists("first.png").unwrap());
+    assert!(storage.exists("second.png").unwrap());
+}
+
+#[actix_rt::test]
+async fn listing_is_paginated_by_cursor_without_stray_objects() {
+    let (storage, store) = storage();
+    for name in &["c.png", "a.png", "b.png"] {
+        stored(&storage, name);
+    }
+    store.put(".gitkeep", b"").unwrap();
+    store.put("notes.txt", b"notes").unwrap();
+
+    let first = body(call(&storage, Config::default(), test::TestRequest::get().uri("/images/?limit=2")).await).await;
+    let names = |page: &Value| page["images"].as_array().unwrap().iter()
+        .map(|image| image["name"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["a", "b"], names(&first));
+    assert_eq!("png", first["images"][0]["format"]);
+    assert_eq!(40, first["images"][0]["width"]);
+
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let uri = format!("/images/?limit=2&cursor={}", cursor);
+    let last = body(call(&storage, Config::default(), test::TestRequest::get().uri(&uri)).await).await;
+    assert_eq!(vec!["c"], names(&last));
+    assert!(last.get("next_cursor").is_none());
+
+    let clamped = body(call(&storage, Config::default(), test::TestRequest::get().uri("/images/?limit=0")).await).await;
+    assert_eq!(vec!["a"], names(&clamped));
+}
+
+#[actix_rt::test]
+async fn resized_image_in_unacceptable_format_is_406() {
+    let (storage, _) = storage();
+    stored(&storage, "cat.png");
+    let request = test::TestRequest::get().uri("/images/cat.png?w=10").header("accept", "image/png;q=0, text/html");
+
+    let response = call(&storage, Config::default(), request).await;
+
+    assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status());
+    assert_eq!("not_acceptable", body(response).await["kind"]);
+}
+
+#[actix_rt::test]
+async fn asynchronous_upload_is_accepted_with_status_url() {
+    let (storage, _) = storage();
+    stored(&storage, "taken.png");
+    let server = Server::new(&storage, Config::default());
+
+    let response = server.call(json_upload("?async=true", &["new.png", "taken.png"])).await;
+
+    assert_eq!(StatusCode::ACCEPTED, response.status());
+    let created = body(response).await;
+    let status_url = created["status"].as_str().unwrap().to_string();
+    assert_eq!(format!("/jobs/{}", created["id"].as_str().unwrap()), status_url);
+
+    let mut status = Value::Null;
+    for _ in 0..100 {
+        let response = server.call(test::TestRequest::get().uri(&status_url)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        status = body(response).await;
+        if status["state"] == "done" {
+            break;
+        }
+        actix_rt::time::delay_for(Duration::from_millis(20)).await;
+    }
+    assert_eq!("done", status["state"]);
+    assert_eq!(2, status["total"]);
+    assert_eq!(200, status["results"][0]["code"]);
+    assert_eq!("name_conflict", status["results"][1]["kind"]);
+    assert!(storage.exists("new.png").unwrap());
+
+    let missing = server.call(test::TestRequest::get().uri("/jobs/unknown")).await;
+    assert_eq!(StatusCode::NOT_FOUND, missing.status());
+}
+
+#[actix_rt::test]
+async fn every_file_of_multipart_upload_is_stored() {
+    let (storage, _) = storage();
+
+    let response = call(&storage, Config::default(), multipart_upload(&[Some("first.png"), Some("second.png")])).await;
+
+    assert_eq!(StatusCode::OK, response.status());
+    let body = body(response).await;
+    assert_eq!("first.png", body[0]["name"]);
+    assert_eq!("second.png", body[1]["name"]);
+    assert!(storage.exists("first.png").unwrap());
+    assert!(storage.exists("second.png").unwrap());
+}
+
+#[actix_rt::test]
+async fn multipart_file_without_filename_is_rejected() {
+    let (storage, _) = storage();
+
+    let response = call(&storage, Config::default(), multipart_upload(&[None, Some("named.png")])).await;
+
+    assert_eq!(StatusCode::OK, response.status());
+    let body = body(response).await;
+    assert_eq!(400, body[0]["code"]);
+    assert_eq!("missing_filename", body[0]["kind"]);
+    assert_eq!(200, body[1]["code"]);
+    assert!(storage.exists("named.png").unwrap());
+}
+
+#[actix_rt::test]
+async fn multipart_file_larger_than_limit_is_rejected() {
+    let (storage, _) = storage();
+    let config = Config { max_file_size: LANDSCAPE_PNG.len() - 1, ..Config::default() };
+
+    let response = call(&storage, config, multipart_upload(&[Some("large.png")])).await;
+
+    let body = body(response).await;
+    assert_eq!(413, body[0]["code"]);
+    assert_eq!("too_large", body[0]["kind"]);
+    assert!(!storage.exists("large.png").unwrap());
+}
+
+#[actix_rt::test]
+async fn downloaded_images_are_in_order_of_request() {
+    let upstream = test::start(|| App::new()
+        .route("/slow.png", web::get().to(|| async {
+            actix_rt::time::delay_for(Duration::from_millis(200)).await;
+            Ok::<_, actix_web::Error>(HttpResponse::Ok().content_type("image/png").body(LANDSCAPE_PNG))
+        }))
+        .route("/fast.png", web::get().to(|| HttpResponse::Ok().content_type("image/png").body(LANDSCAPE_PNG)))
+    );
+    let (storage, _) = storage();
+    let config = Config { url_allow: vec!["127.0.0.1".parse().unwrap()], extract_concurrency: 3, ..Config::default() };
+    let messages = ["slow", "fast", "missing"].iter()
+        .map(|name| json!({ "name": format!("{}.png", name), "url": format!("http://{}/{}.png", upstream.addr(), name) }))
+        .collect::<Vec<_>>();
+
+    let response = call(&storage, config, test::TestRequest::post().uri("/images/from_url").set_json(&messages)).await;
+
+    assert_eq!(StatusCode::OK, response.status());
+    let body = body(response).await;
+    assert_eq!("slow.png", body[0]["name"]);
+    assert_eq!("fast.png", body[1]["name"]);
+    assert_eq!("download_failed", body[2]["kind"]);
+}
+
+#[actix_rt::test]
+async fn batch_larger_than_limit_is_rejected() {
+    let (storage, _) = storage();
+    let config = Config { max_batch_size: 1, ..Config::default() };
+
+    let response = call(&storage, config, json_upload("", &["first.png", "second.png"])).await;
+
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let body = body(response).await;
+    assert_eq!("batch_too_large", body["kind"]);
+    assert_eq!(1, body["details"]["limit"]);
+    assert!(!storage.exists("first.png").unwrap());
+}
+
+#[actix_rt::test]
+async fn error_body_describes_error_with_request_id() {
+    let (storage, _) = storage();
+    let request = test::TestRequest::get().uri("/images/missing.png").header("x-request-id", "trace-1");
+
+    let response = call(&storage, Config::default(), request).await;
+
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+    assert_eq!("trace-1", response.headers().get("x-request-id").unwrap());
+    assert_eq!(json!({
+        "code": 404,
+        "kind": "not_found",
+        "message": "Image missing.png not found",
+        "details": { "name": "missing.png" },
+        "request_id": "trace-1",
+    }), body(response).await);
+}
+
+#[actix_rt::test]
+async fn internal_error_is_not_revealed() {
+    let store = Arc::new(MemoryStore::new());
+    let shared = SharedStore { store, failing: Some("cat.png".to_string()) };
+    let storage = web::Data::new(Gallery::new(Box::new(shared), Layout::ByName, vec![]));
+    stored(&storage, "cat.png");
+
+    let response = call(&storage, Config::default(), test::TestRequest::delete().uri("/images/cat.png")).await;
+
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let request_id = response.headers().get("x-request-id").unwrap().to_str().unwrap().to_string();
+    let body = body(response).await;
+    assert_eq!("Internal server error", body["message"]);
+    assert_eq!(request_id, body["request_id"]);
+}
@@ -0,0 +1,213 @@
+//! Contract of transformation backends.
+//! Run for every backend: `cargo test` and `cargo test --no-default-features --features pure-rust`
+use image_api::image::{
+    ColourType, Encoding, Fit, Image, ImageError, ImageFormat, Limits, Orientation, OutputFormat, Rgb, Source, Strip,
+};
+
+const LANDSCAPE_PNG: &[u8] = include_bytes!("data/landscape.png");
+const LANDSCAPE_BMP: &[u8] = include_bytes!("data/landscape.bmp");
+/// Two frames of 30x20
+const ANIMATED_GIF: &[u8] = include_bytes!("data/animated.gif");
+/// 1x16384 gray pixels
+const TALL_PNG: &[u8] = include_bytes!("data/tall.png");
+/// 40x20 pixels with EXIF orientation 6, displayed as 20x40. Has camera, capture time and location
+const ROTATED_JPEG: &[u8] = include_bytes!("data/rotated.jpg");
+
+fn jpeg() -> Encoding {
+    Encoding::new(OutputFormat::Jpeg)
+}
+
+fn landscape() -> Image {
+    Image::create("landscape".to_string(), LANDSCAPE_PNG.to_vec(), Source::Multipart).unwrap()
+}
+
+#[test]
+fn preview_is_100x100_jpeg() {
+    let preview = landscape().generate_preview(jpeg()).unwrap();
+
+    assert_eq!("preview_landscape", preview.name());
+    assert_eq!("image/jpeg", preview.mime_type());
+    assert_eq!((100, 100), preview.dimensions());
+}
+
+#[test]
+fn rendition_preserves_aspect_ratio() {
+    let rendition = landscape().generate_rendition(10, jpeg()).unwrap();
+
+    assert_eq!((10, 5), rendition.dimensions());
+}
+
+#[test]
+fn rendition_is_not_upscaled() {
+    let rendition = landscape().generate_rendition(1000, jpeg()).unwrap();
+
+    assert_eq!((40, 20), rendition.dimensions());
+}
+
+#[test]
+fn gif_preview_is_generated_from_first_frame() {
+    let gif = Image::create("animated".to_string(), ANIMATED_GIF.to_vec(), Source::Multipart).unwrap();
+    assert_eq!(ImageFormat::Gif, gif.format());
+    assert_eq!((30, 20), gif.dimensions());
+
+    let preview = gif.generate_preview(jpeg()).unwrap();
+
+    assert_eq!("image/jpeg", preview.mime_type());
+    assert_eq!((100, 100), preview.dimensions());
+}
+
+#[test]
+fn created_image_has_metadata() {
+    let url = "https://example.com/landscape.png".to_string();
+    let image = Image::create("landscape".to_string(), LANDSCAPE_PNG.to_vec(), Source::Url { url: url.clone() }).unwrap();
+    let metadata = image.metadata();
+
+    assert_eq!(ImageFormat::Png, metadata.format);
+    assert_eq!("image/png", metadata.mime_type);
+    assert_eq!((40, 20), (metadata.width, metadata.height));
+    assert_eq!(ColourType::Rgb, metadata.colour_type);
+    assert_eq!(8, metadata.bit_depth);
+    assert!(!metadata.progressive);
+    assert_eq!(LANDSCAPE_PNG.len(), metadata.byte_size);
+    assert!(metadata.uploaded.is_some());
+    assert_eq!(Some(Source::Url { url }), metadata.source);
+
+    let preview = image.generate_preview(jpeg()).unwrap();
+    assert_eq!(None, preview.metadata().source);
+}
+
+#[test]
+fn exif_orientation_is_applied() {
+    let image = Image::create("rotated".to_string(), ROTATED_JPEG.to_vec(), Source::Multipart).unwrap();
+    let metadata = image.metadata();
+
+    assert_eq!(Orientation(6), metadata.orientation);
+    assert_eq!((20, 40), image.dimensions());
+    assert_eq!(Some("Canon EOS 5D"), metadata.camera.as_deref());
+    assert_eq!(Some("2020-05-01T12:00:00"), metadata.captured.as_deref());
+
+    assert_eq!((10, 20), image.generate_rendition(10, jpeg()).unwrap().dimensions());
+    assert_eq!((10, 20), image.transform(10, 20, Fit::Fill, jpeg()).unwrap().dimensions());
+}
+
+#[test]
+fn non_ascii_capture_time_is_omitted() {
+    // Multibyte character ends where seconds are expected, in place of terminating zero
+    let original = "2020:05:01 12:00:00\0".as_bytes();
+    let malformed = "2020:05:01 12:00:0\u{e9}".as_bytes();
+    let start = ROTATED_JPEG.windows(original.len()).position(|window| window == original).unwrap();
+    let mut data = ROTATED_JPEG.to_vec();
+    data[start..start + original.len()].copy_from_slice(malformed);
+
+    let image = Image::create("rotated".to_string(), data, Source::Multipart).unwrap();
+
+    assert_eq!(None, image.metadata().captured);
+    assert_eq!(Some("Canon EOS 5D"), image.metadata().camera.as_deref());
+}
+
+#[test]
+fn stripped_exif_keeps_orientation() {
+    let mut image = Image::create("rotated".to_string(), ROTATED_JPEG.to_vec(), Source::Multipart).unwrap();
+
+    image.strip_exif(Strip::All);
+    let stripped = Image::create("rotated".to_string(), image.data().to_vec(), Source::Multipart).unwrap();
+
+    assert_eq!(ROTATED_JPEG.len(), image.data().len());
+    assert_eq!(Orientation(6), stripped.metadata().orientation);
+    assert_eq!((20, 40), stripped.dimensions());
+    assert_eq!(None, stripped.metadata().camera);
+    assert_eq!(None, stripped.metadata().captured);
+}
+
+#[test]
+fn limits_are_checked_before_decoding() {
+    // Header of png declares 50000x50000 pixels, which are never decoded
+    let mut bomb = LANDSCAPE_PNG.to_vec();
+    bomb[16..20].copy_from_slice(&50000u32.to_be_bytes());
+    bomb[20..24].copy_from_slice(&50000u32.to_be_bytes());
+    let bomb = Image::create("bomb".to_string(), bomb, Source::Multipart).unwrap();
+
+    assert!(matches!(bomb.check_limits(&Limits::default()), Err(ImageError::DimensionsExceeded(_))));
+
+    let pixels = Limits { max_pixels: 40 * 20 - 1, ..Limits::default() };
+    assert!(matches!(landscape().check_limits(&pixels), Err(ImageError::DimensionsExceeded(_))));
+    let bytes = Limits { max_bytes: LANDSCAPE_PNG.len() - 1, ..Limits::default() };
+    assert_eq!(Err(ImageError::TooLarge(LANDSCAPE_PNG.len() - 1)), landscape().check_limits(&bytes));
+    assert_eq!(Ok(()), landscape().check_limits(&Limits::default()));
+}
+
+#[test]
+fn bmp_rendition_preserves_aspect_ratio() {
+    let bmp = Image::create("landscape".to_string(), LANDSCAPE_BMP.to_vec(), Source::Multipart).unwrap();
+    assert_eq!(ImageFormat::Bmp, bmp.format());
+
+    let rendition = bmp.generate_rendition(10, jpeg()).unwrap();
+
+    assert_eq!((10, 5), rendition.dimensions());
+}
+
+#[test]
+fn fill_stretches_to_exact_size() {
+    let image = landscape().transform(30, 30, Fit::Fill, jpeg()).unwrap();
+
+    assert_eq!((30, 30), image.dimensions());
+}
+
+#[test]
+fn contain_fits_inside() {
+    let image = landscape().transform(20, 20, Fit::Contain, jpeg()).unwrap();
+
+    assert_eq!((20, 10), image.dimensions());
+}
+
+#[test]
+fn cover_crops_to_exact_size() {
+    let image = landscape().transform(20, 20, Fit::Cover, jpeg()).unwrap();
+
+    assert_eq!((20, 20), image.dimensions());
+}
+
+#[test]
+fn cover_of_extreme_aspect_ratio_crops_before_resizing() {
+    // Scaling the whole source would take 100x1638400 pixels for preview and 2048x33554432 for transformation
+    let tall = Image::create("tall".to_string(), TALL_PNG.to_vec(), Source::Multipart).unwrap();
+    assert_eq!(Ok(()), tall.check_limits(&Limits::default()));
+
+    assert_eq!((100, 100), tall.generate_preview(jpeg()).unwrap().dimensions());
+    assert_eq!((2048, 2048), tall.transform(2048, 2048, Fit::Cover, jpeg()).unwrap().dimensions());
+}
+
+#[test]
+fn pad_extends_to_exact_size() {
+    let image = landscape().transform(20, 20, Fit::Pad(Rgb(255, 255, 255)), jpeg()).unwrap();
+
+    assert_eq!((20, 20), image.dimensions());
+}
+
+#[test]
+fn png_output() {
+    let image = landscape().transform(20, 10, Fit::Fill, Encoding::new(OutputFormat::Png)).unwrap();
+
+    assert_eq!("image/png", image.mime_type());
+    assert_eq!((20, 10), image.dimensions());
+}
+
+#[test]
+fn concurrent_transformations_do_not_interfere() {
+    let threads: Vec<_> = (1..=8u32)
+        .map(|thread| std::thread::spawn(move || {
+            let image = landscape();
+            for iteration in 0..50u32 {
+                // Every thread and iteration expects its own size
+                let width = thread * 4 + iteration % 5;
+                let height = thread * 2 + iteration % 3;
+                let transformed = image.transform(width, height, Fit::Fill, Encoding::new(OutputFormat::Png)).unwrap();
+                assert_eq!((width, height), transformed.dimensions());
+            }
+        }))
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}